@@ -0,0 +1,423 @@
+use anyhow::bail;
+use async_trait::async_trait;
+use reqwest::Client;
+use reqwest::StatusCode;
+use serde::Deserialize;
+use serde::Serialize;
+use serde_json::Value;
+use crate::error::LLMError;
+use crate::generated::ToolCallParameters;
+use crate::ledger::MicroUsd;
+use crate::llm;
+use crate::provider::LLMProvider;
+use crate::provider::ModelInfo;
+use crate::provider::PRICE_VERSION;
+use crate::retry::HttpError;
+use tokio::sync::mpsc::UnboundedSender;
+
+const ANTHROPIC_API_URL: &str = "https://api.anthropic.com";
+const ANTHROPIC_VERSION: &str = "2023-06-01";
+const DEFAULT_MAX_TOKENS: u32 = 4096;
+pub const CLAUDE_35_SONNET: &str = "claude-3-5-sonnet-latest";
+pub const CLAUDE_35_HAIKU: &str = "claude-3-5-haiku-latest";
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ContentBlock {
+	Text {
+		text: String,
+	},
+	ToolUse {
+		id: String,
+		name: String,
+		input: Value,
+	},
+	Image {
+		source: ImageSource,
+	},
+	ToolResult {
+		tool_use_id: String,
+		content: Vec<ContentBlock>,
+	},
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct ImageSource {
+	#[serde(rename = "type")]
+	type_: String,
+	media_type: String,
+	data: String,
+}
+
+impl From<&llm::ImageData> for ContentBlock {
+	fn from(image: &llm::ImageData) -> ContentBlock {
+		ContentBlock::Image {
+			source: ImageSource {
+				type_: "base64".to_string(),
+				media_type: image.media_type.clone(),
+				data: image.data.clone(),
+			},
+		}
+	}
+}
+
+#[derive(Serialize, Debug)]
+struct AnthropicMessage {
+	role: String,
+	content: Vec<ContentBlock>,
+}
+
+#[derive(Serialize, Debug)]
+struct AnthropicTool {
+	name: String,
+	description: String,
+	input_schema: Value,
+}
+
+#[derive(Serialize, Debug)]
+struct AnthropicRequest {
+	model: String,
+	max_tokens: u32,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	system: Option<String>,
+	messages: Vec<AnthropicMessage>,
+	#[serde(skip_serializing_if = "Vec::is_empty")]
+	tools: Vec<AnthropicTool>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	tool_choice: Option<Value>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	temperature: Option<f32>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	top_p: Option<f32>,
+	#[serde(skip_serializing_if = "Vec::is_empty")]
+	stop_sequences: Vec<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Usage {
+	/// Excludes the tokens read from or written to the cache.
+	input_tokens: u32,
+	output_tokens: u32,
+	#[serde(default)]
+	cache_read_input_tokens: u32,
+	#[serde(default)]
+	cache_creation_input_tokens: u32,
+}
+
+#[derive(Deserialize, Debug)]
+struct AnthropicResponse {
+	content: Vec<ContentBlock>,
+	usage: Usage,
+	#[serde(default)]
+	stop_reason: Option<String>,
+}
+
+impl AnthropicResponse {
+	fn to_successfull_gen_response(&self) -> anyhow::Result<llm::SuccessfullGenResponse> {
+		if self.stop_reason.as_deref() == Some("refusal") {
+			return Err(LLMError::ContentFiltered { message: "the model refused to answer".to_string() }.into());
+		}
+		let mut content = String::new();
+		let mut tool_calls = vec![];
+		for block in &self.content {
+			match block {
+				ContentBlock::Text { text } => content += text,
+				ContentBlock::ToolUse { id, name, input } => {
+					tool_calls.push(llm::ToolCall {
+						id: id.clone(),
+						expanded: true,
+						waiting_permission: false,
+						tool: ToolCallParameters::parse(name, &input.to_string())?,
+					});
+				}
+				ContentBlock::Image { .. } => bail!("unexpected image block in response"),
+				ContentBlock::ToolResult { .. } => bail!("unexpected tool_result block in response"),
+			}
+		}
+
+		// Cache writes are billed above the input price, they are counted as
+		// normal input here.
+		let prompt_tokens = self.usage.input_tokens + self.usage.cache_read_input_tokens + self.usage.cache_creation_input_tokens;
+		Ok(llm::SuccessfullGenResponse {
+			prompt_tokens,
+			cached_tokens: self.usage.cache_read_input_tokens,
+			completion_tokens: self.usage.output_tokens,
+			total_tokens: prompt_tokens + self.usage.output_tokens,
+			reasoning_tokens: 0,
+			promt_cost: MicroUsd::default(),
+			completion_cost: MicroUsd::default(),
+			msg: llm::AssistantMsg {
+				content,
+				tool_calls,
+			},
+		})
+	}
+}
+
+/// Anthropic expects strictly alternating roles, so consecutive messages
+/// with the same role (e.g. several tool results) are merged into one.
+fn push_blocks(messages: &mut Vec<AnthropicMessage>, role: &str, blocks: Vec<ContentBlock>) {
+	if blocks.is_empty() {
+		return;
+	}
+	if let Some(last) = messages.last_mut() {
+		if last.role == role {
+			last.content.extend(blocks);
+			return;
+		}
+	}
+	messages.push(AnthropicMessage {
+		role: role.to_string(),
+		content: blocks,
+	});
+}
+
+fn build_request(req: &llm::GenRequest) -> AnthropicRequest {
+	let mut system = vec![];
+	let mut messages = vec![];
+
+	for msg in &req.messages {
+		match msg {
+			llm::LLMMessage::System(text) => system.push(text.clone()),
+			llm::LLMMessage::User(parts) => {
+				push_blocks(&mut messages, "user", parts.iter().map(|part| match part {
+					llm::ContentPart::Text { text } => ContentBlock::Text { text: text.clone() },
+					llm::ContentPart::Image(image) => image.into(),
+				}).collect());
+			}
+			llm::LLMMessage::Assistant(msg) => {
+				let mut blocks = vec![];
+				if !msg.content.is_empty() {
+					blocks.push(ContentBlock::Text { text: msg.content.clone() });
+				}
+				for tool_call in &msg.tool_calls {
+					blocks.push(ContentBlock::ToolUse {
+						id: tool_call.id.clone(),
+						name: tool_call.tool.get_name().to_string(),
+						input: serde_json::from_str(&tool_call.tool.get_args()).unwrap_or(Value::Null),
+					});
+				}
+				push_blocks(&mut messages, "assistant", blocks);
+			}
+			llm::LLMMessage::ToolResponse(r) => {
+				let mut content = vec![ContentBlock::Text { text: r.content.clone() }];
+				content.extend(r.images.iter().map(ContentBlock::from));
+				push_blocks(&mut messages, "user", vec![ContentBlock::ToolResult {
+					tool_use_id: r.id.clone(),
+					content,
+				}]);
+			}
+		}
+	}
+
+	AnthropicRequest {
+		model: req.model.name.clone(),
+		// Anthropic requires max_tokens and has no seed parameter.
+		max_tokens: req.params.max_tokens.unwrap_or(DEFAULT_MAX_TOKENS),
+		system: if system.is_empty() { None } else { Some(system.join("\n")) },
+		messages,
+		tools: req.tools.iter().map(|t| AnthropicTool {
+			name: t.get_name().to_string(),
+			description: t.get_description().to_string(),
+			input_schema: t.get_parameters(),
+		}).collect(),
+		tool_choice: match req.params.parallel_tool_calls {
+			Some(false) if !req.tools.is_empty() => Some(serde_json::json!({ "type": "auto", "disable_parallel_tool_use": true })),
+			_ => None,
+		},
+		temperature: req.params.temperature,
+		top_p: req.params.top_p,
+		stop_sequences: req.params.stop.clone(),
+	}
+}
+
+async fn send(req: llm::GenRequest, client: &Client, base_url: &str, apikey: &str) -> anyhow::Result<llm::SuccessfullGenResponse> {
+	let body = serde_json::to_string(&build_request(&req))?;
+	log::info!("body: {}", body);
+
+	let res = client
+		.post(format!("{}/v1/messages", base_url))
+		.header("x-api-key", apikey)
+		.header("anthropic-version", ANTHROPIC_VERSION)
+		.header("Content-Type", "application/json")
+		.body(body)
+		.send()
+		.await?;
+
+	let status_code = res.status();
+	let headers = res.headers().clone();
+	let text = res.text().await?;
+
+	if status_code != StatusCode::OK {
+		log::error!("request failed with code {}", status_code);
+		log::error!("response: {}", text);
+		return Err(HttpError::new(status_code, &headers, text).into());
+	}
+
+	log::info!("response: {}", text);
+	let response: AnthropicResponse = serde_json::from_str(&text)?;
+	response.to_successfull_gen_response()
+}
+
+pub struct AnthropicProvider {
+	client: Client,
+}
+
+impl AnthropicProvider {
+	pub fn new(client: Client) -> AnthropicProvider {
+		AnthropicProvider { client }
+	}
+}
+
+#[async_trait]
+impl LLMProvider for AnthropicProvider {
+	fn id(&self) -> &str {
+		"anthropic"
+	}
+
+	fn models(&self) -> Vec<ModelInfo> {
+		vec![
+			ModelInfo {
+				name: CLAUDE_35_SONNET.to_string(),
+				context_window: 200_000,
+				tools: true,
+				vision: true,
+				reasoning: false,
+				input_price: 3.0,
+				output_price: 15.0,
+				cached_input_price: 0.30,
+				price_version: PRICE_VERSION.to_string(),
+			},
+			ModelInfo {
+				name: CLAUDE_35_HAIKU.to_string(),
+				context_window: 200_000,
+				tools: true,
+				vision: false,
+				reasoning: false,
+				input_price: 0.80,
+				output_price: 4.0,
+				cached_input_price: 0.08,
+				price_version: PRICE_VERSION.to_string(),
+			},
+		]
+	}
+
+	async fn gen(&self, req: llm::GenRequest, _tx: &UnboundedSender<llm::GenResult>) -> anyhow::Result<llm::SuccessfullGenResponse> {
+		let apikey = match std::env::var("ANTHROPIC_API_KEY") {
+			Ok(key) => key,
+			Err(_) => {
+				return Err(LLMError::auth_missing("ANTHROPIC_API_KEY").into());
+			}
+		};
+		let base_url = std::env::var("ANTHROPIC_BASE_URL").unwrap_or_else(|_| ANTHROPIC_API_URL.to_string());
+		send(req, &self.client, &base_url, &apikey).await
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::generated::ReadFile;
+	use crate::generated::Tool;
+	use wiremock::matchers::header;
+	use wiremock::matchers::method;
+	use wiremock::matchers::path;
+	use wiremock::Mock;
+	use wiremock::MockServer;
+	use wiremock::ResponseTemplate;
+
+	fn tool_loop_request() -> llm::GenRequest {
+		llm::GenRequest {
+			model: llm::LLMModel::new("anthropic", CLAUDE_35_HAIKU),
+			messages: vec![
+				llm::LLMMessage::System("You are puppycoder".to_string()),
+				llm::LLMMessage::user("Read main.rs"),
+				llm::LLMMessage::Assistant(llm::AssistantMsg {
+					content: "".to_string(),
+					tool_calls: vec![llm::ToolCall {
+						id: "toolu_1".to_string(),
+						expanded: true,
+						waiting_permission: false,
+						tool: ToolCallParameters::ReadFile(ReadFile {
+							path: "main.rs".to_string(),
+							start_line_number: 0,
+							linenumber_count: 10,
+						}),
+					}],
+				}),
+				llm::LLMMessage::ToolResponse(llm::ToolResponse {
+					id: "toolu_1".to_string(),
+					content: "fn main() {}".to_string(),
+					images: Vec::new(),
+				}),
+				llm::LLMMessage::user("Explain it"),
+			],
+			tools: vec![Tool::ReadFile],
+			..Default::default()
+		}
+	}
+
+	#[test]
+	fn test_build_request() {
+		let body = serde_json::to_value(build_request(&tool_loop_request())).unwrap();
+
+		assert_eq!(body["system"], "You are puppycoder");
+		assert_eq!(body["tools"][0]["name"], "read_file");
+		let messages = body["messages"].as_array().unwrap();
+		assert_eq!(messages.len(), 3);
+		assert_eq!(messages[1]["content"][0]["type"], "tool_use");
+		assert_eq!(messages[1]["content"][0]["input"]["path"], "main.rs");
+		assert_eq!(messages[2]["role"], "user");
+		assert_eq!(messages[2]["content"][0]["type"], "tool_result");
+		assert_eq!(messages[2]["content"][0]["tool_use_id"], "toolu_1");
+		assert_eq!(messages[2]["content"][0]["content"][0]["text"], "fn main() {}");
+		assert_eq!(messages[2]["content"][1]["text"], "Explain it");
+		assert_eq!(body["max_tokens"], DEFAULT_MAX_TOKENS);
+		assert!(body.get("temperature").is_none());
+
+		let mut req = tool_loop_request();
+		req.params = llm::GenParams {
+			temperature: Some(0.0),
+			max_tokens: Some(256),
+			stop: vec!["END".to_string()],
+			parallel_tool_calls: Some(false),
+			..Default::default()
+		};
+		let body = serde_json::to_value(build_request(&req)).unwrap();
+		assert_eq!(body["temperature"], 0.0);
+		assert_eq!(body["max_tokens"], 256);
+		assert_eq!(body["stop_sequences"][0], "END");
+		assert_eq!(body["tool_choice"]["disable_parallel_tool_use"], true);
+	}
+
+	#[tokio::test]
+	async fn test_gen_against_mock_server() {
+		let server = MockServer::start().await;
+		Mock::given(method("POST"))
+			.and(path("/v1/messages"))
+			.and(header("x-api-key", "test-key"))
+			.respond_with(ResponseTemplate::new(200).set_body_string(r#"{
+				"id": "msg_1",
+				"type": "message",
+				"role": "assistant",
+				"model": "claude-3-5-haiku-latest",
+				"stop_reason": "tool_use",
+				"content": [
+					{ "type": "text", "text": "Let me look." },
+					{ "type": "tool_use", "id": "toolu_2", "name": "list_folder_content", "input": { "path": "src" } }
+				],
+				"usage": { "input_tokens": 1000, "output_tokens": 100 }
+			}"#))
+			.mount(&server)
+			.await;
+
+		let res = send(tool_loop_request(), &Client::new(), &server.uri(), "test-key").await.unwrap();
+
+		assert_eq!(res.msg.content, "Let me look.");
+		assert_eq!(res.msg.tool_calls[0].id, "toolu_2");
+		assert_eq!(res.msg.tool_calls[0].tool.get_name(), "list_folder_content");
+		assert_eq!(res.prompt_tokens, 1000);
+		assert_eq!(res.completion_tokens, 100);
+		assert_eq!(res.total_tokens, 1100);
+	}
+}
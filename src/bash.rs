@@ -0,0 +1,267 @@
+use std::collections::VecDeque;
+use std::path::Path;
+use std::process::Stdio;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use serde::Serialize;
+use tokio::io::AsyncBufRead;
+use tokio::io::AsyncBufReadExt;
+use tokio::io::AsyncWriteExt;
+use tokio::io::BufReader;
+use tokio::process::Child;
+use tokio::process::ChildStderr;
+use tokio::process::ChildStdin;
+use tokio::process::ChildStdout;
+use tokio::process::Command;
+use tokio::sync::Mutex;
+
+/// Output kept of each stream, half from the start and half from the end.
+/// Errors are usually at the end of long build logs.
+pub const MAX_OUTPUT_BYTES: usize = 16 * 1024;
+pub const DEFAULT_TIMEOUT_SECS: u64 = 60;
+
+/// Keeps the first and last `cap / 2` bytes written to it without holding
+/// the whole output in memory.
+#[derive(Debug)]
+pub struct CappedOutput {
+	cap: usize,
+	head: Vec<u8>,
+	tail: VecDeque<u8>,
+	/// Bytes dropped between `head` and `tail`.
+	truncated: usize,
+}
+
+impl CappedOutput {
+	pub fn new(cap: usize) -> CappedOutput {
+		CappedOutput {
+			cap,
+			head: Vec::new(),
+			tail: VecDeque::new(),
+			truncated: 0,
+		}
+	}
+
+	pub fn push(&mut self, mut bytes: &[u8]) {
+		let head_cap = self.cap / 2;
+		if self.head.len() < head_cap {
+			let n = bytes.len().min(head_cap - self.head.len());
+			self.head.extend_from_slice(&bytes[..n]);
+			bytes = &bytes[n..];
+		}
+		let tail_cap = self.cap - head_cap;
+		self.tail.extend(bytes);
+		if self.tail.len() > tail_cap {
+			let excess = self.tail.len() - tail_cap;
+			self.tail.drain(..excess);
+			self.truncated += excess;
+		}
+	}
+
+	/// Removes the last byte written.
+	pub fn pop(&mut self) {
+		if self.tail.pop_back().is_none() {
+			self.head.pop();
+		}
+	}
+
+	/// Lossy UTF-8 since the cut can land inside a character.
+	pub fn finish(self) -> String {
+		let tail: Vec<u8> = self.tail.into_iter().collect();
+		let head = String::from_utf8_lossy(&self.head);
+		let tail = String::from_utf8_lossy(&tail);
+		if self.truncated == 0 {
+			format!("{}{}", head, tail)
+		} else {
+			format!("{}\n[... {} bytes truncated ...]\n{}", head, self.truncated, tail)
+		}
+	}
+}
+
+/// Result of a command as sent to the model.
+#[derive(Debug, Serialize)]
+pub struct BashOutput {
+	/// Not set when the command was killed, e.g. by the timeout.
+	pub exit_code: Option<i32>,
+	/// The shell is restarted after a timeout, so the working directory
+	/// and variables are back to the defaults.
+	pub timed_out: bool,
+	/// Working directory of the shell after the command. Not set when the
+	/// shell did not survive the command.
+	pub cwd: Option<String>,
+	pub stdout: String,
+	pub stderr: String,
+}
+
+/// Reads lines into `output` until the line starting with `marker`, which
+/// is returned without the marker. None if the stream ended first.
+async fn read_until_marker(reader: &mut (impl AsyncBufRead + Unpin), marker: &str, output: &mut CappedOutput) -> std::io::Result<Option<String>> {
+	let mut line = Vec::new();
+	loop {
+		line.clear();
+		if reader.read_until(b'\n', &mut line).await? == 0 {
+			return Ok(None);
+		}
+		if let Some(rest) = line.strip_prefix(marker.as_bytes()) {
+			// The newline printed before the marker.
+			output.pop();
+			return Ok(Some(String::from_utf8_lossy(rest).trim().to_string()));
+		}
+		output.push(&line);
+	}
+}
+
+fn new_marker() -> String {
+	static NEXT: AtomicU64 = AtomicU64::new(0);
+	let nanos = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos();
+	format!("__PUPPYCODER_{}_{}_{}__", std::process::id(), nanos, NEXT.fetch_add(1, Ordering::Relaxed))
+}
+
+/// A bash process commands are written to one after another, so the working
+/// directory, variables and activated virtualenvs carry over between them.
+#[derive(Debug)]
+pub struct ShellSession {
+	child: Child,
+	stdin: ChildStdin,
+	stdout: BufReader<ChildStdout>,
+	stderr: BufReader<ChildStderr>,
+}
+
+impl ShellSession {
+	pub fn start(folder: &Path) -> anyhow::Result<ShellSession> {
+		let mut child = Command::new("bash")
+			.arg("--noprofile")
+			.arg("--norc")
+			.current_dir(folder)
+			.stdin(Stdio::piped())
+			.stdout(Stdio::piped())
+			.stderr(Stdio::piped())
+			.kill_on_drop(true)
+			.spawn()?;
+		Ok(ShellSession {
+			stdin: child.stdin.take().unwrap(),
+			stdout: BufReader::new(child.stdout.take().unwrap()),
+			stderr: BufReader::new(child.stderr.take().unwrap()),
+			child,
+		})
+	}
+
+	/// Runs `cmd` and returns its exit code and the working directory after
+	/// it. The command is passed through a quoted heredoc and `eval` so any
+	/// quoting or syntax error in it stays contained, and its stdin is
+	/// /dev/null so it can not read the commands which follow. None if the
+	/// shell exited, e.g. because the command ran `exit`.
+	async fn exec(&mut self, cmd: &str, out: &mut CappedOutput, err: &mut CappedOutput) -> anyhow::Result<Option<(i32, String)>> {
+		let marker = new_marker();
+		let script = format!(
+			"eval \"$(cat <<'{marker}'\n{cmd}\n{marker}\n)\" < /dev/null\n\
+			printf '\\n%s %d %s\\n' '{marker}' \"$?\" \"$PWD\"\n\
+			printf '\\n%s\\n' '{marker}' >&2\n",
+		);
+		self.stdin.write_all(script.as_bytes()).await?;
+		self.stdin.flush().await?;
+
+		let (status, _) = tokio::try_join!(
+			read_until_marker(&mut self.stdout, &marker, out),
+			read_until_marker(&mut self.stderr, &marker, err),
+		)?;
+		let status = match status {
+			Some(status) => status,
+			None => return Ok(None),
+		};
+		let (code, cwd) = status.split_once(' ').unwrap_or((&status, ""));
+		Ok(Some((code.parse()?, cwd.to_string())))
+	}
+}
+
+/// Lazily started shell session of a project. A session is only kept after
+/// a command finished cleanly, one which timed out, was cancelled or exited
+/// the shell takes the session with it and the next command starts a new one.
+#[derive(Debug, Default)]
+pub struct Shell {
+	session: Mutex<Option<ShellSession>>,
+}
+
+impl Shell {
+	pub async fn run(&self, cmd: &str, folder: &Path, timeout: Duration) -> anyhow::Result<BashOutput> {
+		let mut guard = self.session.lock().await;
+		let mut session = match guard.take() {
+			Some(session) => session,
+			None => ShellSession::start(folder)?,
+		};
+		let mut out = CappedOutput::new(MAX_OUTPUT_BYTES);
+		let mut err = CappedOutput::new(MAX_OUTPUT_BYTES);
+
+		let res = tokio::time::timeout(timeout, session.exec(cmd, &mut out, &mut err)).await;
+		let (exit_code, timed_out, cwd) = match res {
+			Ok(Ok(Some((code, cwd)))) => {
+				*guard = Some(session);
+				(Some(code), false, Some(cwd))
+			}
+			Ok(Ok(None)) => (session.child.wait().await?.code(), false, None),
+			Ok(Err(e)) => return Err(e),
+			Err(_) => {
+				log::warn!("command timed out after {:?}: {}", timeout, cmd);
+				(None, true, None)
+			}
+		};
+		Ok(BashOutput {
+			exit_code,
+			timed_out,
+			cwd,
+			stdout: out.finish(),
+			stderr: err.finish(),
+		})
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_capped_output() {
+		let mut output = CappedOutput::new(8);
+		output.push(b"abc");
+		assert_eq!(output.finish(), "abc");
+
+		let mut output = CappedOutput::new(8);
+		output.push(b"0123456789");
+		output.push(b"abcdef");
+		assert_eq!(output.finish(), "0123\n[... 8 bytes truncated ...]\ncdef");
+	}
+
+	#[tokio::test]
+	async fn test_shell_session() {
+		let folder = std::env::temp_dir();
+		let timeout = Duration::from_secs(10);
+		let shell = Shell::default();
+		let res = shell.run("mkdir -p puppy-shell-test && cd puppy-shell-test; export PUPPY=woof; echo oops >&2; false", &folder, timeout).await.unwrap();
+		assert_eq!(res.exit_code, Some(1));
+		assert_eq!(res.stderr, "oops\n");
+		assert!(res.cwd.unwrap().ends_with("puppy-shell-test"));
+
+		// State carries over and quotes or unterminated input do not break the markers.
+		let res = shell.run("echo \"$PUPPY\" 'in' \"$(basename \"$PWD\")\"; printf 'no newline'", &folder, timeout).await.unwrap();
+		assert_eq!(res.stdout, "woof in puppy-shell-test\nno newline");
+		let res = shell.run("echo 'unterminated", &folder, timeout).await.unwrap();
+		assert_eq!(res.exit_code, Some(2));
+		let res = shell.run("cat; echo $PUPPY", &folder, timeout).await.unwrap();
+		assert_eq!(res.stdout, "woof\n");
+
+		// A command which times out takes the session with it.
+		let res = shell.run("echo started; sleep 10", &folder, Duration::from_millis(200)).await.unwrap();
+		assert!(res.timed_out);
+		assert_eq!(res.exit_code, None);
+		assert_eq!(res.stdout, "started\n");
+		let res = shell.run("echo ${PUPPY:-reset}; exit 4", &folder, timeout).await.unwrap();
+		assert_eq!(res.stdout, "reset\n");
+		assert_eq!(res.exit_code, Some(4));
+		assert_eq!(res.cwd, None);
+
+		std::fs::remove_dir_all(folder.join("puppy-shell-test")).unwrap();
+	}
+}
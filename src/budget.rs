@@ -0,0 +1,149 @@
+use serde::Deserialize;
+use serde::Serialize;
+
+fn default_warning_threshold() -> f32 {
+	0.8
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BudgetScope {
+	/// Everything spent on the project.
+	Project,
+	/// Spent since the app was started.
+	Session,
+	/// Spent since the last message sent by the user.
+	Task,
+}
+
+impl BudgetScope {
+	pub const ALL: [BudgetScope; 3] = [BudgetScope::Project, BudgetScope::Session, BudgetScope::Task];
+
+	pub fn name(&self) -> &'static str {
+		match self {
+			BudgetScope::Project => "project",
+			BudgetScope::Session => "session",
+			BudgetScope::Task => "task",
+		}
+	}
+}
+
+/// Spending limits of a project in USD. `None` means no limit.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Budget {
+	#[serde(default)]
+	pub project_limit: Option<f32>,
+	#[serde(default)]
+	pub session_limit: Option<f32>,
+	#[serde(default)]
+	pub task_limit: Option<f32>,
+	/// Fraction of a limit after which a warning is shown.
+	#[serde(default = "default_warning_threshold")]
+	pub warning_threshold: f32,
+}
+
+impl Default for Budget {
+	fn default() -> Budget {
+		Budget {
+			project_limit: None,
+			session_limit: None,
+			task_limit: None,
+			warning_threshold: default_warning_threshold(),
+		}
+	}
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Spending {
+	pub project: f32,
+	pub session: f32,
+	pub task: f32,
+}
+
+impl Spending {
+	pub fn get(&self, scope: BudgetScope) -> f32 {
+		match scope {
+			BudgetScope::Project => self.project,
+			BudgetScope::Session => self.session,
+			BudgetScope::Task => self.task,
+		}
+	}
+}
+
+/// Request which was not sent because it would have gone over a limit.
+#[derive(Debug, Clone)]
+pub struct BudgetExceeded {
+	pub scope: BudgetScope,
+	pub limit: f32,
+	pub spent: f32,
+	pub estimate: f32,
+}
+
+impl BudgetExceeded {
+	/// Limit offered when the user chooses to raise it.
+	pub fn raised_limit(&self) -> f32 {
+		(self.limit * 2.0).max(self.spent + self.estimate)
+	}
+}
+
+impl Budget {
+	pub fn limit(&self, scope: BudgetScope) -> Option<f32> {
+		match scope {
+			BudgetScope::Project => self.project_limit,
+			BudgetScope::Session => self.session_limit,
+			BudgetScope::Task => self.task_limit,
+		}
+	}
+
+	pub fn limit_mut(&mut self, scope: BudgetScope) -> &mut Option<f32> {
+		match scope {
+			BudgetScope::Project => &mut self.project_limit,
+			BudgetScope::Session => &mut self.session_limit,
+			BudgetScope::Task => &mut self.task_limit,
+		}
+	}
+
+	/// Returns the first limit which spending `estimate` more would exceed.
+	pub fn check(&self, spending: &Spending, estimate: f32) -> Option<BudgetExceeded> {
+		BudgetScope::ALL.into_iter().find_map(|scope| {
+			let limit = self.limit(scope)?;
+			let spent = spending.get(scope);
+			if spent + estimate > limit {
+				Some(BudgetExceeded { scope, limit, spent, estimate })
+			} else {
+				None
+			}
+		})
+	}
+
+	/// Whether spending in `scope` has passed the warning threshold.
+	pub fn is_near_limit(&self, spending: &Spending, scope: BudgetScope) -> bool {
+		self.limit(scope).is_some_and(|limit| spending.get(scope) >= limit * self.warning_threshold)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_check() {
+		let budget = Budget {
+			project_limit: Some(10.0),
+			task_limit: Some(1.0),
+			..Default::default()
+		};
+		let spending = Spending {
+			project: 5.0,
+			session: 5.0,
+			task: 0.9,
+		};
+
+		assert!(budget.check(&spending, 0.05).is_none());
+		let exceeded = budget.check(&spending, 0.2).unwrap();
+		assert_eq!(exceeded.scope, BudgetScope::Task);
+		assert_eq!(exceeded.limit, 1.0);
+		assert!(budget.is_near_limit(&spending, BudgetScope::Task));
+		assert!(!budget.is_near_limit(&spending, BudgetScope::Project));
+		assert!(!budget.is_near_limit(&spending, BudgetScope::Session));
+	}
+}
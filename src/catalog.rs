@@ -0,0 +1,81 @@
+use std::path::Path;
+
+use crate::llm::LLMModel;
+use crate::provider::ModelInfo;
+use crate::provider::ProviderRegistry;
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct CatalogModel {
+	pub provider: String,
+	#[serde(flatten)]
+	pub info: ModelInfo,
+}
+
+/// Models shown in the UI together with their prices. Loaded from
+/// `models.json` in the app dir so prices can change without a release.
+#[derive(Debug, Clone, Default)]
+pub struct ModelCatalog {
+	pub models: Vec<CatalogModel>,
+}
+
+impl ModelCatalog {
+	/// Adds the built in models of every registered provider which are not listed yet.
+	pub fn add_defaults(&mut self, registry: &ProviderRegistry) {
+		for (provider, info) in registry.models() {
+			if self.model_info(&LLMModel::new(&provider, &info.name)).is_none() {
+				self.models.push(CatalogModel { provider, info });
+			}
+		}
+	}
+
+	/// Replaces the entries with the ones in `path`. If the file does not
+	/// exist it is created from the current entries so there is something to edit.
+	pub fn load_or_create(&mut self, path: &Path) -> anyhow::Result<()> {
+		if !path.exists() {
+			std::fs::write(path, serde_json::to_string_pretty(&self.models)?)?;
+			return Ok(());
+		}
+		let content = std::fs::read_to_string(path)?;
+		self.models = serde_json::from_str(&content)?;
+		Ok(())
+	}
+
+	pub fn model_info(&self, model: &LLMModel) -> Option<ModelInfo> {
+		self.models.iter()
+			.find(|m| m.provider == model.provider && m.info.name == model.name)
+			.map(|m| m.info.clone())
+	}
+
+	pub fn models(&self) -> Vec<LLMModel> {
+		self.models.iter().map(|m| LLMModel::new(&m.provider, &m.info.name)).collect()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::ledger::MicroUsd;
+	use crate::llm::LLMClient;
+
+	#[test]
+	fn test_catalog_file_overrides_prices() {
+		let path = std::env::temp_dir().join(format!("puppycoder-models-{}.json", std::process::id()));
+		let _ = std::fs::remove_file(&path);
+		let model = LLMModel::new("openai", "gpt-4o");
+
+		let mut catalog = ModelCatalog::default();
+		catalog.add_defaults(LLMClient::new().providers());
+		catalog.load_or_create(&path).unwrap();
+		assert!(path.exists());
+		assert_eq!(catalog.model_info(&model).unwrap().input_cost(1_000_000), MicroUsd::from_usd(2.5));
+
+		let content = std::fs::read_to_string(&path).unwrap().replace("\"input_price\": 2.5,", "\"input_price\": 2.0,");
+		std::fs::write(&path, content).unwrap();
+		let mut catalog = ModelCatalog::default();
+		catalog.load_or_create(&path).unwrap();
+		std::fs::remove_file(&path).unwrap();
+
+		assert_eq!(catalog.model_info(&model).unwrap().input_cost(1_000_000), MicroUsd::from_usd(2.0));
+		assert!(catalog.models().contains(&LLMModel::new("anthropic", "claude-3-5-haiku-latest")));
+	}
+}
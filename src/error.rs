@@ -0,0 +1,178 @@
+use std::fmt;
+
+use reqwest::StatusCode;
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::retry::HttpError;
+
+/// Phrases providers use in 400 responses when the prompt does not fit.
+const CONTEXT_OVERFLOW_MARKERS: [&str; 5] = [
+	"context_length_exceeded",
+	"maximum context length",
+	"prompt is too long",
+	"input token count",
+	"too many tokens",
+];
+/// Phrases providers use when a request is blocked by moderation.
+const CONTENT_FILTER_MARKERS: [&str; 3] = [
+	"content_filter",
+	"content_policy",
+	"safety",
+];
+
+/// Why a request to a model failed. Stored in the history so the user can
+/// see what went wrong and what to do about it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum LLMError {
+	/// The API key is not set (`env_var`) or was rejected by the provider.
+	Auth { env_var: Option<String>, message: String },
+	/// Still rate limited after all retries.
+	RateLimited { message: String },
+	/// The prompt did not fit in the model's context window.
+	ContextOverflow { message: String },
+	/// The prompt or the response was blocked by moderation.
+	ContentFiltered { message: String },
+	/// The provider is down or overloaded.
+	Unavailable { message: String },
+	Network { message: String },
+	/// The provider answered with something we could not parse.
+	MalformedResponse { message: String },
+	Other { message: String },
+}
+
+impl LLMError {
+	pub fn auth_missing(env_var: &str) -> LLMError {
+		LLMError::Auth {
+			env_var: Some(env_var.to_string()),
+			message: format!("{} not set", env_var),
+		}
+	}
+
+	pub fn malformed(message: &str) -> LLMError {
+		LLMError::MalformedResponse { message: message.to_string() }
+	}
+
+	/// Sorts an error returned by a provider into one of the kinds.
+	pub fn classify(err: &anyhow::Error) -> LLMError {
+		if let Some(err) = err.downcast_ref::<LLMError>() {
+			return err.clone();
+		}
+		let message = err.to_string();
+		if let Some(err) = err.downcast_ref::<HttpError>() {
+			let body = err.body.to_lowercase();
+			return match err.status {
+				StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => LLMError::Auth { env_var: None, message },
+				StatusCode::TOO_MANY_REQUESTS => LLMError::RateLimited { message },
+				_ if CONTEXT_OVERFLOW_MARKERS.iter().any(|m| body.contains(m)) => LLMError::ContextOverflow { message },
+				StatusCode::PAYLOAD_TOO_LARGE => LLMError::ContextOverflow { message },
+				_ if CONTENT_FILTER_MARKERS.iter().any(|m| body.contains(m)) => LLMError::ContentFiltered { message },
+				status if status.is_server_error() => LLMError::Unavailable { message },
+				_ => LLMError::Other { message },
+			};
+		}
+		if let Some(err) = err.downcast_ref::<reqwest::Error>() {
+			return if err.is_decode() {
+				LLMError::MalformedResponse { message }
+			} else {
+				LLMError::Network { message }
+			};
+		}
+		if err.is::<serde_json::Error>() {
+			return LLMError::MalformedResponse { message };
+		}
+		LLMError::Other { message }
+	}
+
+	pub fn title(&self) -> &'static str {
+		match self {
+			LLMError::Auth { .. } => "Authentication failed",
+			LLMError::RateLimited { .. } => "Rate limited",
+			LLMError::ContextOverflow { .. } => "Context too long",
+			LLMError::ContentFiltered { .. } => "Blocked by content filter",
+			LLMError::Unavailable { .. } => "Provider unavailable",
+			LLMError::Network { .. } => "Network error",
+			LLMError::MalformedResponse { .. } => "Malformed response",
+			LLMError::Other { .. } => "Request failed",
+		}
+	}
+
+	pub fn message(&self) -> &str {
+		match self {
+			LLMError::Auth { message, .. }
+			| LLMError::RateLimited { message }
+			| LLMError::ContextOverflow { message }
+			| LLMError::ContentFiltered { message }
+			| LLMError::Unavailable { message }
+			| LLMError::Network { message }
+			| LLMError::MalformedResponse { message }
+			| LLMError::Other { message } => message,
+		}
+	}
+
+	/// What the user can do to get the conversation going again.
+	pub fn suggestion(&self) -> String {
+		match self {
+			LLMError::Auth { env_var: Some(env_var), .. } => {
+				format!("Set {} in the environment or in .env and restart puppycoder", env_var)
+			}
+			LLMError::Auth { env_var: None, .. } => "Check that the API key is valid and has access to the model".to_string(),
+			LLMError::RateLimited { .. } => "Wait a moment and retry, or lower the request rate".to_string(),
+			LLMError::ContextOverflow { .. } => "Compact the context and retry".to_string(),
+			LLMError::ContentFiltered { .. } => "Rephrase the last message".to_string(),
+			LLMError::Unavailable { .. } => "Retry later or add a fallback model to the project".to_string(),
+			LLMError::Network { .. } => "Check your connection and retry".to_string(),
+			LLMError::MalformedResponse { .. } => "Retry or switch to another model".to_string(),
+			LLMError::Other { .. } => "Retry".to_string(),
+		}
+	}
+
+	/// Whether sending the same request again can succeed.
+	pub fn can_retry(&self) -> bool {
+		!matches!(self, LLMError::Auth { .. } | LLMError::ContextOverflow { .. } | LLMError::ContentFiltered { .. })
+	}
+
+	/// Whether another model can answer where this one failed. Problems with
+	/// the request itself or the API key are left to the user.
+	pub fn can_fall_back(&self) -> bool {
+		matches!(self, LLMError::RateLimited { .. } | LLMError::ContextOverflow { .. } | LLMError::Unavailable { .. } | LLMError::Network { .. })
+	}
+}
+
+impl fmt::Display for LLMError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{}: {}", self.title(), self.message())
+	}
+}
+
+impl std::error::Error for LLMError {}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use reqwest::header::HeaderMap;
+
+	fn http_error(status: u16, body: &str) -> anyhow::Error {
+		HttpError::new(StatusCode::from_u16(status).unwrap(), &HeaderMap::new(), body.to_string()).into()
+	}
+
+	#[test]
+	fn test_classify() {
+		assert_eq!(LLMError::classify(&LLMError::auth_missing("OPENAI_API_KEY").into()), LLMError::Auth {
+			env_var: Some("OPENAI_API_KEY".to_string()),
+			message: "OPENAI_API_KEY not set".to_string(),
+		});
+		assert!(matches!(LLMError::classify(&http_error(401, "")), LLMError::Auth { env_var: None, .. }));
+		assert!(matches!(LLMError::classify(&http_error(429, "")), LLMError::RateLimited { .. }));
+		let body = r#"{"error":{"code":"context_length_exceeded","message":"This model's maximum context length is 128000 tokens."}}"#;
+		assert!(matches!(LLMError::classify(&http_error(400, body)), LLMError::ContextOverflow { .. }));
+		let body = r#"{"type":"error","error":{"type":"invalid_request_error","message":"prompt is too long: 210000 tokens > 200000 maximum"}}"#;
+		assert!(matches!(LLMError::classify(&http_error(400, body)), LLMError::ContextOverflow { .. }));
+		assert!(matches!(LLMError::classify(&http_error(400, r#"{"error":{"code":"content_filter"}}"#)), LLMError::ContentFiltered { .. }));
+		assert!(matches!(LLMError::classify(&http_error(529, r#"{"type":"overloaded_error"}"#)), LLMError::Unavailable { .. }));
+		assert!(matches!(LLMError::classify(&http_error(404, "")), LLMError::Other { .. }));
+		let err = serde_json::from_str::<serde_json::Value>("{").unwrap_err();
+		assert!(matches!(LLMError::classify(&err.into()), LLMError::MalformedResponse { .. }));
+	}
+}
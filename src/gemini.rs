@@ -0,0 +1,505 @@
+use std::collections::HashMap;
+
+use anyhow::bail;
+use async_trait::async_trait;
+use reqwest::Client;
+use reqwest::StatusCode;
+use serde::Deserialize;
+use serde::Serialize;
+use serde_json::Value;
+use crate::error::LLMError;
+use crate::generated::ToolCallParameters;
+use crate::ledger::MicroUsd;
+use crate::llm;
+use crate::provider::LLMProvider;
+use crate::provider::ModelInfo;
+use crate::provider::PRICE_VERSION;
+use crate::retry::HttpError;
+use tokio::sync::mpsc::UnboundedSender;
+
+const GEMINI_API_URL: &str = "https://generativelanguage.googleapis.com";
+pub const GEMINI_15_PRO: &str = "gemini-1.5-pro";
+pub const GEMINI_15_FLASH: &str = "gemini-1.5-flash";
+/// Finish reasons of a response stopped by moderation.
+const BLOCK_REASONS: [&str; 4] = ["SAFETY", "RECITATION", "BLOCKLIST", "PROHIBITED_CONTENT"];
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+struct FunctionCall {
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	id: Option<String>,
+	name: String,
+	#[serde(default)]
+	args: Value,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+struct FunctionResponse {
+	name: String,
+	response: Value,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+enum Part {
+	Text(String),
+	FunctionCall(FunctionCall),
+	FunctionResponse(FunctionResponse),
+	InlineData(InlineData),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+struct InlineData {
+	mime_type: String,
+	data: String,
+}
+
+impl From<&llm::ImageData> for Part {
+	fn from(image: &llm::ImageData) -> Part {
+		Part::InlineData(InlineData {
+			mime_type: image.media_type.clone(),
+			data: image.data.clone(),
+		})
+	}
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct Content {
+	role: String,
+	parts: Vec<Part>,
+}
+
+#[derive(Serialize, Debug)]
+struct SystemInstruction {
+	parts: Vec<Part>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct FunctionDeclaration {
+	name: String,
+	description: String,
+	parameters: Value,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct GeminiTool {
+	function_declarations: Vec<FunctionDeclaration>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct GeminiRequest {
+	#[serde(skip_serializing_if = "Option::is_none")]
+	system_instruction: Option<SystemInstruction>,
+	contents: Vec<Content>,
+	#[serde(skip_serializing_if = "Vec::is_empty")]
+	tools: Vec<GeminiTool>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	generation_config: Option<GenerationConfig>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct GenerationConfig {
+	#[serde(skip_serializing_if = "Option::is_none")]
+	temperature: Option<f32>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	top_p: Option<f32>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	max_output_tokens: Option<u32>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	seed: Option<i64>,
+	#[serde(skip_serializing_if = "Vec::is_empty")]
+	stop_sequences: Vec<String>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct Candidate {
+	#[serde(default)]
+	content: Option<Content>,
+	#[serde(default)]
+	finish_reason: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct PromptFeedback {
+	#[serde(default)]
+	block_reason: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+struct UsageMetadata {
+	#[serde(default)]
+	prompt_token_count: u32,
+	#[serde(default)]
+	candidates_token_count: u32,
+	#[serde(default)]
+	total_token_count: u32,
+	/// Part of `prompt_token_count` served from cached content.
+	#[serde(default)]
+	cached_content_token_count: u32,
+	/// Thinking tokens, not included in `candidates_token_count`.
+	#[serde(default)]
+	thoughts_token_count: u32,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct GeminiResponse {
+	#[serde(default)]
+	candidates: Vec<Candidate>,
+	#[serde(default)]
+	prompt_feedback: Option<PromptFeedback>,
+	#[serde(default)]
+	usage_metadata: UsageMetadata,
+}
+
+impl GeminiResponse {
+	fn to_successfull_gen_response(&self) -> anyhow::Result<llm::SuccessfullGenResponse> {
+		// A blocked prompt gets no candidates, a blocked response comes without content.
+		if let Some(reason) = self.prompt_feedback.as_ref().and_then(|f| f.block_reason.as_ref()) {
+			return Err(LLMError::ContentFiltered { message: format!("prompt blocked: {}", reason) }.into());
+		}
+		let first_candidate = self.candidates.first().ok_or_else(|| LLMError::malformed("no candidates"))?;
+		if let Some(reason) = first_candidate.finish_reason.as_ref().filter(|r| BLOCK_REASONS.contains(&r.as_str())) {
+			return Err(LLMError::ContentFiltered { message: format!("response blocked: {}", reason) }.into());
+		}
+		let parts = first_candidate.content.as_ref().map(|c| c.parts.as_slice()).unwrap_or_default();
+
+		let mut content = String::new();
+		let mut tool_calls = vec![];
+		for (inx, part) in parts.iter().enumerate() {
+			match part {
+				Part::Text(text) => content += text,
+				Part::FunctionCall(call) => {
+					// Older Gemini models do not return call ids, but our history
+					// needs one to pair the tool response with its call.
+					let id = call.id.clone().unwrap_or_else(|| {
+						format!("call_{}_{}", chrono::Utc::now().timestamp_millis(), inx)
+					});
+					tool_calls.push(llm::ToolCall {
+						id,
+						expanded: true,
+						waiting_permission: false,
+						tool: ToolCallParameters::parse(&call.name, &call.args.to_string())?,
+					});
+				}
+				Part::FunctionResponse(_) => bail!("unexpected functionResponse part in response"),
+				Part::InlineData(_) => bail!("unexpected inlineData part in response"),
+			}
+		}
+
+		let usage = &self.usage_metadata;
+		Ok(llm::SuccessfullGenResponse {
+			prompt_tokens: usage.prompt_token_count,
+			cached_tokens: usage.cached_content_token_count,
+			completion_tokens: usage.candidates_token_count + usage.thoughts_token_count,
+			total_tokens: usage.total_token_count,
+			reasoning_tokens: usage.thoughts_token_count,
+			promt_cost: MicroUsd::default(),
+			completion_cost: MicroUsd::default(),
+			msg: llm::AssistantMsg {
+				content,
+				tool_calls,
+			},
+		})
+	}
+}
+
+/// Gemini accepts an OpenAPI subset where types are upper case and
+/// keywords like `additionalProperties` are rejected.
+fn to_gemini_schema(schema: &Value) -> Value {
+	let obj = match schema.as_object() {
+		Some(obj) => obj,
+		None => return schema.clone(),
+	};
+	let mut out = serde_json::Map::new();
+	for (key, value) in obj {
+		match key.as_str() {
+			"type" => {
+				out.insert(key.clone(), Value::String(value.as_str().unwrap_or("string").to_uppercase()));
+			}
+			"properties" => {
+				let props = value.as_object().map(|props| {
+					props.iter().map(|(name, prop)| (name.clone(), to_gemini_schema(prop))).collect()
+				}).unwrap_or_default();
+				out.insert(key.clone(), Value::Object(props));
+			}
+			"items" => {
+				out.insert(key.clone(), to_gemini_schema(value));
+			}
+			"description" | "required" | "enum" | "format" | "nullable" => {
+				out.insert(key.clone(), value.clone());
+			}
+			_ => {}
+		}
+	}
+	Value::Object(out)
+}
+
+fn push_parts(contents: &mut Vec<Content>, role: &str, parts: Vec<Part>) {
+	if parts.is_empty() {
+		return;
+	}
+	if let Some(last) = contents.last_mut() {
+		if last.role == role {
+			last.parts.extend(parts);
+			return;
+		}
+	}
+	contents.push(Content {
+		role: role.to_string(),
+		parts,
+	});
+}
+
+fn build_request(req: &llm::GenRequest) -> GeminiRequest {
+	let mut system = vec![];
+	let mut contents = vec![];
+	// functionResponse is matched by name, so remember which tool each call id used.
+	let mut call_names: HashMap<&str, &str> = HashMap::new();
+
+	for msg in &req.messages {
+		match msg {
+			llm::LLMMessage::System(text) => system.push(Part::Text(text.clone())),
+			llm::LLMMessage::User(parts) => push_parts(&mut contents, "user", parts.iter().map(|part| match part {
+				llm::ContentPart::Text { text } => Part::Text(text.clone()),
+				llm::ContentPart::Image(image) => image.into(),
+			}).collect()),
+			llm::LLMMessage::Assistant(msg) => {
+				let mut parts = vec![];
+				if !msg.content.is_empty() {
+					parts.push(Part::Text(msg.content.clone()));
+				}
+				for tool_call in &msg.tool_calls {
+					call_names.insert(&tool_call.id, tool_call.tool.get_name());
+					parts.push(Part::FunctionCall(FunctionCall {
+						id: None,
+						name: tool_call.tool.get_name().to_string(),
+						args: serde_json::from_str(&tool_call.tool.get_args()).unwrap_or(Value::Null),
+					}));
+				}
+				push_parts(&mut contents, "model", parts);
+			}
+			llm::LLMMessage::ToolResponse(r) => {
+				let name = call_names.get(r.id.as_str()).copied().unwrap_or_default();
+				// Function responses only take JSON so images follow as separate parts.
+				let mut parts = vec![Part::FunctionResponse(FunctionResponse {
+					name: name.to_string(),
+					response: serde_json::json!({ "content": r.content }),
+				})];
+				parts.extend(r.images.iter().map(Part::from));
+				push_parts(&mut contents, "user", parts);
+			}
+		}
+	}
+
+	GeminiRequest {
+		system_instruction: if system.is_empty() { None } else { Some(SystemInstruction { parts: system }) },
+		contents,
+		tools: if req.tools.is_empty() {
+			vec![]
+		} else {
+			vec![GeminiTool {
+				function_declarations: req.tools.iter().map(|t| FunctionDeclaration {
+					name: t.get_name().to_string(),
+					description: t.get_description().to_string(),
+					parameters: to_gemini_schema(&t.get_parameters()),
+				}).collect(),
+			}]
+		},
+		generation_config: if req.params == llm::GenParams::default() {
+			None
+		} else {
+			// Gemini has no switch for parallel function calls.
+			Some(GenerationConfig {
+				temperature: req.params.temperature,
+				top_p: req.params.top_p,
+				max_output_tokens: req.params.max_tokens,
+				seed: req.params.seed,
+				stop_sequences: req.params.stop.clone(),
+			})
+		},
+	}
+}
+
+async fn send(req: llm::GenRequest, client: &Client, base_url: &str, apikey: &str) -> anyhow::Result<llm::SuccessfullGenResponse> {
+	let body = serde_json::to_string(&build_request(&req))?;
+	log::info!("body: {}", body);
+
+	let res = client
+		.post(format!("{}/v1beta/models/{}:generateContent", base_url, req.model.name))
+		.header("x-goog-api-key", apikey)
+		.header("Content-Type", "application/json")
+		.body(body)
+		.send()
+		.await?;
+
+	let status_code = res.status();
+	let headers = res.headers().clone();
+	let text = res.text().await?;
+
+	if status_code != StatusCode::OK {
+		log::error!("request failed with code {}", status_code);
+		log::error!("response: {}", text);
+		return Err(HttpError::new(status_code, &headers, text).into());
+	}
+
+	log::info!("response: {}", text);
+	let response: GeminiResponse = serde_json::from_str(&text)?;
+	response.to_successfull_gen_response()
+}
+
+pub struct GeminiProvider {
+	client: Client,
+}
+
+impl GeminiProvider {
+	pub fn new(client: Client) -> GeminiProvider {
+		GeminiProvider { client }
+	}
+}
+
+#[async_trait]
+impl LLMProvider for GeminiProvider {
+	fn id(&self) -> &str {
+		"gemini"
+	}
+
+	fn models(&self) -> Vec<ModelInfo> {
+		vec![
+			ModelInfo {
+				name: GEMINI_15_PRO.to_string(),
+				context_window: 2_000_000,
+				tools: true,
+				vision: true,
+				reasoning: false,
+				input_price: 1.25,
+				output_price: 5.0,
+				cached_input_price: 0.3125,
+				price_version: PRICE_VERSION.to_string(),
+			},
+			ModelInfo {
+				name: GEMINI_15_FLASH.to_string(),
+				context_window: 1_000_000,
+				tools: true,
+				vision: true,
+				reasoning: false,
+				input_price: 0.075,
+				output_price: 0.30,
+				cached_input_price: 0.01875,
+				price_version: PRICE_VERSION.to_string(),
+			},
+		]
+	}
+
+	async fn gen(&self, req: llm::GenRequest, _tx: &UnboundedSender<llm::GenResult>) -> anyhow::Result<llm::SuccessfullGenResponse> {
+		let apikey = match std::env::var("GEMINI_API_KEY") {
+			Ok(key) => key,
+			Err(_) => {
+				return Err(LLMError::auth_missing("GEMINI_API_KEY").into());
+			}
+		};
+		let base_url = std::env::var("GEMINI_BASE_URL").unwrap_or_else(|_| GEMINI_API_URL.to_string());
+		send(req, &self.client, &base_url, &apikey).await
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::generated::ListFolderContent;
+	use crate::generated::Tool;
+	use wiremock::matchers::header;
+	use wiremock::matchers::method;
+	use wiremock::matchers::path;
+	use wiremock::Mock;
+	use wiremock::MockServer;
+	use wiremock::ResponseTemplate;
+
+	#[test]
+	fn test_schema_translation() {
+		let schema = to_gemini_schema(&Tool::FindInFile.get_parameters());
+
+		assert_eq!(schema["type"], "OBJECT");
+		assert_eq!(schema["properties"]["path"]["type"], "STRING");
+		assert_eq!(schema["required"], serde_json::json!(["path", "pattern"]));
+		assert!(to_gemini_schema(&serde_json::json!({ "type": "object", "additionalProperties": false }))
+			.get("additionalProperties").is_none());
+	}
+
+	#[test]
+	fn test_function_response_uses_call_name() {
+		let req = llm::GenRequest {
+			model: llm::LLMModel::new("gemini", GEMINI_15_FLASH),
+			messages: vec![
+				llm::LLMMessage::user("What is in src?"),
+				llm::LLMMessage::Assistant(llm::AssistantMsg {
+					content: "".to_string(),
+					tool_calls: vec![llm::ToolCall {
+						id: "call_1".to_string(),
+						expanded: true,
+						waiting_permission: false,
+						tool: ToolCallParameters::ListFolderContent(ListFolderContent { path: "src".to_string() }),
+					}],
+				}),
+				llm::LLMMessage::ToolResponse(llm::ToolResponse {
+					id: "call_1".to_string(),
+					content: "main.rs".to_string(),
+					images: Vec::new(),
+				}),
+			],
+			tools: vec![Tool::ListFolderContent],
+			..Default::default()
+		};
+		let body = serde_json::to_value(build_request(&req)).unwrap();
+
+		assert_eq!(body["contents"][1]["role"], "model");
+		assert_eq!(body["contents"][1]["parts"][0]["functionCall"]["args"]["path"], "src");
+		assert_eq!(body["contents"][2]["parts"][0]["functionResponse"]["name"], "list_folder_content");
+		assert_eq!(body["tools"][0]["functionDeclarations"][0]["name"], "list_folder_content");
+	}
+
+	#[tokio::test]
+	async fn test_gen_against_mock_server() {
+		let server = MockServer::start().await;
+		Mock::given(method("POST"))
+			.and(path("/v1beta/models/gemini-1.5-flash:generateContent"))
+			.and(header("x-goog-api-key", "test-key"))
+			.respond_with(ResponseTemplate::new(200).set_body_string(r#"{
+				"candidates": [{
+					"content": {
+						"role": "model",
+						"parts": [
+							{ "text": "Reading it." },
+							{ "functionCall": { "name": "read_file", "args": { "path": "main.rs", "start_line_number": 0, "linenumber_count": 20 } } }
+						]
+					},
+					"finishReason": "STOP"
+				}],
+				"usageMetadata": { "promptTokenCount": 200, "candidatesTokenCount": 20, "totalTokenCount": 220 }
+			}"#))
+			.mount(&server)
+			.await;
+
+		let req = llm::GenRequest {
+			model: llm::LLMModel::new("gemini", GEMINI_15_FLASH),
+			messages: vec![llm::LLMMessage::user("Read main.rs")],
+			tools: vec![Tool::ReadFile],
+			..Default::default()
+		};
+		let res = send(req, &Client::new(), &server.uri(), "test-key").await.unwrap();
+
+		assert_eq!(res.msg.content, "Reading it.");
+		assert_eq!(res.msg.tool_calls.len(), 1);
+		assert!(matches!(&res.msg.tool_calls[0].tool, ToolCallParameters::ReadFile(r) if r.path == "main.rs"));
+		assert_eq!(res.total_tokens, 220);
+	}
+}
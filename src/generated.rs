@@ -10,7 +10,12 @@ pub enum Tool {
 	ForgetMemory,
 	ListFolderContent,
 	FindInFile,
-	ExecuteBashCmd
+	ExecuteBashCmd,
+	ViewImage,
+	StartProcess,
+	ReadProcessOutput,
+	ListProcesses,
+	StopProcess
 }
 
 impl ToString for Tool {
@@ -26,6 +31,11 @@ impl ToString for Tool {
 			Tool::ListFolderContent => "List Folder Content".to_string(),
 			Tool::FindInFile => "Find In File".to_string(),
 			Tool::ExecuteBashCmd => "Execute Bash Cmd".to_string(),
+			Tool::ViewImage => "View Image".to_string(),
+			Tool::StartProcess => "Start Process".to_string(),
+			Tool::ReadProcessOutput => "Read Process Output".to_string(),
+			Tool::ListProcesses => "List Processes".to_string(),
+			Tool::StopProcess => "Stop Process".to_string(),
 		}
 	}
 }
@@ -43,6 +53,11 @@ impl Tool {
 			Tool::ListFolderContent => "list_folder_content",
 			Tool::FindInFile => "find_in_file",
 			Tool::ExecuteBashCmd => "execute_bash_cmd",
+			Tool::ViewImage => "view_image",
+			Tool::StartProcess => "start_process",
+			Tool::ReadProcessOutput => "read_process_output",
+			Tool::ListProcesses => "list_processes",
+			Tool::StopProcess => "stop_process",
 		}
 	}
 
@@ -57,7 +72,12 @@ impl Tool {
 			Tool::ForgetMemory => "You can forget memories with this tool to free up space",
 			Tool::ListFolderContent => "List folder content",
 			Tool::FindInFile => "Find content in file",
-			Tool::ExecuteBashCmd => "Execute bash command",
+			Tool::ExecuteBashCmd => "Run a bash command in a shell session started in the project folder. The working directory and environment carry over to the next command. Returns the exit code, stdout and stderr as JSON. Long output is cut in the middle and commands are killed after a timeout",
+			Tool::ViewImage => "Look at a PNG or JPEG image in the project folder, like a screenshot or a design mockup",
+			Tool::StartProcess => "Start a command in the background in the project folder, like a dev server or a file watcher, and return right away. Use read_process_output to see what it prints",
+			Tool::ReadProcessOutput => "Check whether a background process is still running and read the last lines of its output",
+			Tool::ListProcesses => "List the background processes of the project and whether they are running",
+			Tool::StopProcess => "Kill a background process and the processes it started",
 		}
 	}
 
@@ -73,11 +93,16 @@ impl Tool {
 			Tool::ListFolderContent => serde_json::json!({"properties":{"path":{"description":"Path of the folder you want to list","type":"string"}},"required":["path"],"type":"object"}),
 			Tool::FindInFile => serde_json::json!({"properties":{"path":{"description":"Path of the file in which you want to search","type":"string"},"pattern":{"description":"Pattern you want to search","type":"string"}},"required":["path","pattern"],"type":"object"}),
 			Tool::ExecuteBashCmd => serde_json::json!({"properties":{"cmd":{"description":"Command you want to execute","type":"string"}},"required":["cmd"],"type":"object"}),
+			Tool::ViewImage => serde_json::json!({"properties":{"path":{"description":"Path of the image you want to see","type":"string"}},"required":["path"],"type":"object"}),
+			Tool::StartProcess => serde_json::json!({"properties":{"cmd":{"description":"Bash command to run","type":"string"},"name":{"description":"Name you refer to the process with","type":"string"}},"required":["cmd","name"],"type":"object"}),
+			Tool::ReadProcessOutput => serde_json::json!({"properties":{"lines":{"description":"Number of lines from the end of the output, 50 if not given","type":"integer"},"name":{"description":"Name of the process","type":"string"}},"required":["name"],"type":"object"}),
+			Tool::ListProcesses => serde_json::json!({"properties":{},"required":[],"type":"object"}),
+			Tool::StopProcess => serde_json::json!({"properties":{"name":{"description":"Name of the process","type":"string"}},"required":["name"],"type":"object"}),
 		}
 	}
 }
 
-pub const TOOLS: [Tool; 10] = [
+pub const TOOLS: [Tool; 15] = [
 	Tool::ReadFile,
 	Tool::WriteFile,
 	Tool::RemoveFile,
@@ -88,6 +113,11 @@ pub const TOOLS: [Tool; 10] = [
 	Tool::ListFolderContent,
 	Tool::FindInFile,
 	Tool::ExecuteBashCmd,
+	Tool::ViewImage,
+	Tool::StartProcess,
+	Tool::ReadProcessOutput,
+	Tool::ListProcesses,
+	Tool::StopProcess,
 ];
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct ReadFile {
@@ -145,6 +175,33 @@ pub struct FindInFile {
 pub struct ExecuteBashCmd {
 	pub cmd: String,
 }
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct ViewImage {
+	pub path: String,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct StartProcess {
+	pub cmd: String,
+	pub name: String,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct ReadProcessOutput {
+	pub lines: Option<u32>,
+	pub name: String,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct ListProcesses {
+
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct StopProcess {
+	pub name: String,
+}
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub enum ToolCallParameters {
 	ReadFile(ReadFile),
@@ -156,7 +213,12 @@ pub enum ToolCallParameters {
 	ForgetMemory(ForgetMemory),
 	ListFolderContent(ListFolderContent),
 	FindInFile(FindInFile),
-	ExecuteBashCmd(ExecuteBashCmd)
+	ExecuteBashCmd(ExecuteBashCmd),
+	ViewImage(ViewImage),
+	StartProcess(StartProcess),
+	ReadProcessOutput(ReadProcessOutput),
+	ListProcesses(ListProcesses),
+	StopProcess(StopProcess)
 }
 
 impl ToolCallParameters {
@@ -172,6 +234,11 @@ impl ToolCallParameters {
 			ToolCallParameters::ListFolderContent(_) => "list_folder_content",
 			ToolCallParameters::FindInFile(_) => "find_in_file",
 			ToolCallParameters::ExecuteBashCmd(_) => "execute_bash_cmd",
+			ToolCallParameters::ViewImage(_) => "view_image",
+			ToolCallParameters::StartProcess(_) => "start_process",
+			ToolCallParameters::ReadProcessOutput(_) => "read_process_output",
+			ToolCallParameters::ListProcesses(_) => "list_processes",
+			ToolCallParameters::StopProcess(_) => "stop_process",
 		}
 	}
 
@@ -187,6 +254,11 @@ impl ToolCallParameters {
 			ToolCallParameters::ListFolderContent(args) => serde_json::to_string(args).unwrap(),
 			ToolCallParameters::FindInFile(args) => serde_json::to_string(args).unwrap(),
 			ToolCallParameters::ExecuteBashCmd(args) => serde_json::to_string(args).unwrap(),
+			ToolCallParameters::ViewImage(args) => serde_json::to_string(args).unwrap(),
+			ToolCallParameters::StartProcess(args) => serde_json::to_string(args).unwrap(),
+			ToolCallParameters::ReadProcessOutput(args) => serde_json::to_string(args).unwrap(),
+			ToolCallParameters::ListProcesses(args) => serde_json::to_string(args).unwrap(),
+			ToolCallParameters::StopProcess(args) => serde_json::to_string(args).unwrap(),
 		}
 	}
 
@@ -202,6 +274,11 @@ impl ToolCallParameters {
 			"list_folder_content" => Ok(ToolCallParameters::ListFolderContent(serde_json::from_str(args)?)),
 			"find_in_file" => Ok(ToolCallParameters::FindInFile(serde_json::from_str(args)?)),
 			"execute_bash_cmd" => Ok(ToolCallParameters::ExecuteBashCmd(serde_json::from_str(args)?)),
+			"view_image" => Ok(ToolCallParameters::ViewImage(serde_json::from_str(args)?)),
+			"start_process" => Ok(ToolCallParameters::StartProcess(serde_json::from_str(args)?)),
+			"read_process_output" => Ok(ToolCallParameters::ReadProcessOutput(serde_json::from_str(args)?)),
+			"list_processes" => Ok(ToolCallParameters::ListProcesses(serde_json::from_str(args)?)),
+			"stop_process" => Ok(ToolCallParameters::StopProcess(serde_json::from_str(args)?)),
 			_ => anyhow::bail!("Unknown tool: {}", name),
 		}
 	}
@@ -1,11 +1,43 @@
 use chrono::DateTime;
 use chrono::Utc;
+use crate::error::LLMError;
+use crate::llm::LLMModel;
 use crate::LLMMessage;
 
+/// Stored in place of output that was cut off by the user.
+pub const CANCELLED_MSG: &str = "Cancelled by user";
+
+/// Untagged so histories saved before errors were recorded still load.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(untagged)]
+pub enum HistoryEntry {
+	Message(LLMMessage),
+	/// Failed request. Shown to the user but never sent to the model.
+	Error(LLMError),
+	/// Failed request retried with the next model of the fallback chain.
+	Fallback(Fallback),
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Fallback {
+	pub from: LLMModel,
+	pub to: LLMModel,
+	pub error: LLMError,
+}
+
 #[derive(Debug, serde::Serialize, serde::Deserialize)]
 pub struct HistoryItem {
 	pub timestamp: DateTime<Utc>,
-	pub content: LLMMessage,
+	pub content: HistoryEntry,
+}
+
+impl HistoryItem {
+	pub fn message(&self) -> Option<&LLMMessage> {
+		match &self.content {
+			HistoryEntry::Message(msg) => Some(msg),
+			HistoryEntry::Error(_) | HistoryEntry::Fallback(_) => None,
+		}
+	}
 }
 
 #[derive(Debug, Default, serde::Serialize, serde::Deserialize)]
@@ -23,11 +55,25 @@ impl History {
 	pub fn add_message(&mut self, message: LLMMessage) {
 		self.items.push(HistoryItem {
 			timestamp: Utc::now(),
-			content: message,
+			content: HistoryEntry::Message(message),
+		});
+	}
+
+	pub fn add_error(&mut self, error: LLMError) {
+		self.items.push(HistoryItem {
+			timestamp: Utc::now(),
+			content: HistoryEntry::Error(error),
+		});
+	}
+
+	pub fn add_fallback(&mut self, fallback: Fallback) {
+		self.items.push(HistoryItem {
+			timestamp: Utc::now(),
+			content: HistoryEntry::Fallback(fallback),
 		});
 	}
 
 	pub fn get_context(&self) -> Vec<LLMMessage> {
-		self.items.iter().map(|item| item.content.clone()).collect()
+		self.items.iter().filter_map(|item| item.message().cloned()).collect()
 	}
 }
\ No newline at end of file
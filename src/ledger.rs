@@ -0,0 +1,272 @@
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use std::iter::Sum;
+use std::ops::Add;
+use std::ops::AddAssign;
+use std::path::Path;
+
+use chrono::DateTime;
+use chrono::NaiveDate;
+use chrono::Utc;
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::llm::LLMModel;
+
+/// Amount of money in millionths of a USD. Costs are summed as integers so
+/// totals do not drift over thousands of requests.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct MicroUsd(pub u64);
+
+impl MicroUsd {
+	pub fn from_usd(usd: f64) -> MicroUsd {
+		MicroUsd((usd * 1_000_000.0).round().max(0.0) as u64)
+	}
+
+	/// Cost of `token_count` tokens at `price` USD per million tokens.
+	pub fn for_tokens(token_count: u32, price: f32) -> MicroUsd {
+		// A million tokens cost `price` USD so a token costs `price` micro USD.
+		MicroUsd((token_count as f64 * price as f64).round() as u64)
+	}
+
+	pub fn usd(self) -> f64 {
+		self.0 as f64 / 1_000_000.0
+	}
+
+	pub fn saturating_sub(self, other: MicroUsd) -> MicroUsd {
+		MicroUsd(self.0.saturating_sub(other.0))
+	}
+}
+
+impl Add for MicroUsd {
+	type Output = MicroUsd;
+
+	fn add(self, other: MicroUsd) -> MicroUsd {
+		MicroUsd(self.0 + other.0)
+	}
+}
+
+impl AddAssign for MicroUsd {
+	fn add_assign(&mut self, other: MicroUsd) {
+		self.0 += other.0;
+	}
+}
+
+impl Sum for MicroUsd {
+	fn sum<I: Iterator<Item = MicroUsd>>(iter: I) -> MicroUsd {
+		iter.fold(MicroUsd::default(), Add::add)
+	}
+}
+
+/// One billed request.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LedgerEntry {
+	pub timestamp: DateTime<Utc>,
+	pub model: LLMModel,
+	/// `price_version` of the catalog entry the costs were computed with.
+	pub price_version: String,
+	pub prompt_tokens: u32,
+	pub cached_tokens: u32,
+	pub completion_tokens: u32,
+	pub reasoning_tokens: u32,
+	pub prompt_cost: MicroUsd,
+	pub completion_cost: MicroUsd,
+	/// What the cached tokens would have cost more at the normal input price.
+	pub cache_savings: MicroUsd,
+}
+
+impl LedgerEntry {
+	pub fn cost(&self) -> MicroUsd {
+		self.prompt_cost + self.completion_cost
+	}
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct LedgerTotals {
+	pub requests: u32,
+	pub prompt_tokens: u64,
+	pub cached_tokens: u64,
+	pub completion_tokens: u64,
+	pub reasoning_tokens: u64,
+	pub prompt_cost: MicroUsd,
+	pub completion_cost: MicroUsd,
+	pub cache_savings: MicroUsd,
+}
+
+impl LedgerTotals {
+	fn add(&mut self, entry: &LedgerEntry) {
+		self.requests += 1;
+		self.prompt_tokens += entry.prompt_tokens as u64;
+		self.cached_tokens += entry.cached_tokens as u64;
+		self.completion_tokens += entry.completion_tokens as u64;
+		self.reasoning_tokens += entry.reasoning_tokens as u64;
+		self.prompt_cost += entry.prompt_cost;
+		self.completion_cost += entry.completion_cost;
+		self.cache_savings += entry.cache_savings;
+	}
+
+	pub fn cost(&self) -> MicroUsd {
+		self.prompt_cost + self.completion_cost
+	}
+}
+
+/// Every request billed for a project.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Ledger {
+	#[serde(default)]
+	pub entries: Vec<LedgerEntry>,
+	/// Spent before the ledger was kept, when only the totals were saved.
+	#[serde(default)]
+	pub carried_over: MicroUsd,
+}
+
+impl Ledger {
+	pub fn add(&mut self, entry: LedgerEntry) {
+		self.entries.push(entry);
+	}
+
+	pub fn total(&self) -> LedgerTotals {
+		let mut totals = LedgerTotals::default();
+		for entry in &self.entries {
+			totals.add(entry);
+		}
+		totals
+	}
+
+	/// Total cost including what was spent before the ledger was kept.
+	pub fn total_cost(&self) -> MicroUsd {
+		self.total().cost() + self.carried_over
+	}
+
+	/// Totals per UTC day, oldest first.
+	pub fn by_day(&self) -> Vec<(NaiveDate, LedgerTotals)> {
+		let mut days: BTreeMap<NaiveDate, LedgerTotals> = BTreeMap::new();
+		for entry in &self.entries {
+			days.entry(entry.timestamp.date_naive()).or_default().add(entry);
+		}
+		days.into_iter().collect()
+	}
+
+	/// Totals per model, most expensive first.
+	pub fn by_model(&self) -> Vec<(LLMModel, LedgerTotals)> {
+		let mut models: BTreeMap<String, (LLMModel, LedgerTotals)> = BTreeMap::new();
+		for entry in &self.entries {
+			models.entry(entry.model.key())
+				.or_insert_with(|| (entry.model.clone(), LedgerTotals::default()))
+				.1.add(entry);
+		}
+		let mut models: Vec<_> = models.into_values().collect();
+		models.sort_by_key(|(_, totals)| Reverse(totals.cost()));
+		models
+	}
+}
+
+/// Currency costs are shown in. Prices are in USD so other currencies need
+/// an exchange rate, which the user sets since rates change daily.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DisplayCurrency {
+	pub code: String,
+	/// Units of the currency per USD.
+	pub rate: f64,
+}
+
+impl Default for DisplayCurrency {
+	fn default() -> DisplayCurrency {
+		DisplayCurrency {
+			code: "USD".to_string(),
+			rate: 1.0,
+		}
+	}
+}
+
+impl DisplayCurrency {
+	pub fn load(path: &Path) -> anyhow::Result<DisplayCurrency> {
+		if !path.exists() {
+			return Ok(DisplayCurrency::default());
+		}
+		let content = std::fs::read_to_string(path)?;
+		Ok(serde_json::from_str(&content)?)
+	}
+
+	pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+		std::fs::write(path, serde_json::to_string_pretty(self)?)?;
+		Ok(())
+	}
+
+	/// Small amounts get more decimals since single requests often cost
+	/// fractions of a cent.
+	pub fn format(&self, amount: MicroUsd) -> String {
+		let value = amount.usd() * self.rate;
+		let decimals = if value == 0.0 || value >= 1.0 { 2 } else { 4 };
+		if self.code == "USD" {
+			format!("${:.*}", decimals, value)
+		} else {
+			format!("{:.*} {}", decimals, value, self.code)
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use chrono::TimeZone;
+
+	fn entry(day: u32, model: &str, prompt_cost: u64) -> LedgerEntry {
+		LedgerEntry {
+			timestamp: Utc.with_ymd_and_hms(2025, 1, day, 12, 0, 0).unwrap(),
+			model: LLMModel::new("openai", model),
+			price_version: "2025-01".to_string(),
+			prompt_tokens: 1000,
+			cached_tokens: 0,
+			completion_tokens: 100,
+			reasoning_tokens: 0,
+			prompt_cost: MicroUsd(prompt_cost),
+			completion_cost: MicroUsd(1),
+			cache_savings: MicroUsd(0),
+		}
+	}
+
+	#[test]
+	fn test_totals_do_not_drift() {
+		let mut ledger = Ledger::default();
+		// 0.1 is not exact in binary, summing it as f32 drifts within a few thousand steps.
+		for _ in 0..10_000 {
+			ledger.add(LedgerEntry {
+				prompt_cost: MicroUsd::from_usd(0.1),
+				completion_cost: MicroUsd(0),
+				..entry(1, "gpt-4o", 0)
+			});
+		}
+		assert_eq!(ledger.total_cost(), MicroUsd::from_usd(1000.0));
+		assert_eq!(MicroUsd::for_tokens(1_000_000, 2.5), MicroUsd::from_usd(2.5));
+	}
+
+	#[test]
+	fn test_aggregates() {
+		let mut ledger = Ledger::default();
+		ledger.add(entry(2, "gpt-4o-mini", 10));
+		ledger.add(entry(1, "gpt-4o", 100));
+		ledger.add(entry(2, "gpt-4o", 100));
+
+		let days = ledger.by_day();
+		assert_eq!(days.len(), 2);
+		assert_eq!(days[0].0, NaiveDate::from_ymd_opt(2025, 1, 1).unwrap());
+		assert_eq!(days[1].1.requests, 2);
+		assert_eq!(days[1].1.cost(), MicroUsd(112));
+
+		let models = ledger.by_model();
+		assert_eq!(models[0].0.name, "gpt-4o");
+		assert_eq!(models[0].1.cost(), MicroUsd(202));
+		assert_eq!(models[1].1.prompt_tokens, 1000);
+	}
+
+	#[test]
+	fn test_format() {
+		let usd = DisplayCurrency::default();
+		assert_eq!(usd.format(MicroUsd::from_usd(12.5)), "$12.50");
+		assert_eq!(usd.format(MicroUsd(1234)), "$0.0012");
+		let eur = DisplayCurrency { code: "EUR".to_string(), rate: 0.5 };
+		assert_eq!(eur.format(MicroUsd::from_usd(10.0)), "5.00 EUR");
+	}
+}
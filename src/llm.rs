@@ -1,11 +1,32 @@
+use anyhow::bail;
+use base64::prelude::BASE64_STANDARD;
+use base64::Engine;
+use serde::Deserialize;
 use serde_json::Value;
 use tokio::sync::mpsc::UnboundedReceiver;
 use tokio::sync::mpsc::UnboundedSender;
+use tokio::task::JoinHandle;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
 use crate::generated::*;
-use crate::openai;
+use crate::anthropic::AnthropicProvider;
+use crate::catalog::ModelCatalog;
+use crate::error::LLMError;
+use crate::ledger::MicroUsd;
+use crate::gemini::GeminiProvider;
+use crate::openai::CustomOpenAIProvider;
+use crate::openai::OpenAIProvider;
+use crate::provider::LLMProvider;
+use crate::provider::ModelInfo;
+use crate::provider::ProviderRegistry;
+use crate::retry::RetryInfo;
+use crate::retry::RetryPolicy;
+use crate::vcr::Cassette;
+use crate::vcr::VcrMode;
 
-pub const GPT_4O: &str = "gpt-4o";
-pub const GPT_4O_MINI: &str = "gpt-4o-mini";
+/// Provider id of the per project OpenAI compatible endpoint.
+pub const CUSTOM_PROVIDER: &str = "custom";
 
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct ToolCall {
@@ -26,70 +47,221 @@ pub struct AssistantMsg {
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct ToolResponse {
 	pub id: String,
-	pub content: String
+	pub content: String,
+	#[serde(default, skip_serializing_if = "Vec::is_empty")]
+	pub images: Vec<ImageData>,
 }
 
-const GPT_40_INPUT_COST: f32 = 2.5 / 1_000_000.0;
-const GPT_40_OUTPUT_COST: f32 = 10.0 / 1_000_000.0;
-const GPT_40_MINI_INPUT_COST: f32 = 0.150 / 1_000_000.0;
-const GPT_40_MINI_OUTPUT_COST: f32 = 0.600 / 1_000_000.0;
+/// Largest image accepted, the limit of the OpenAI API.
+const MAX_IMAGE_SIZE: u64 = 20 * 1024 * 1024;
 
-#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
-pub enum LLMModel {
-	GPT4O,
-	GPT4OMini,
+/// Base64 encoded PNG or JPEG image.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct ImageData {
+	pub media_type: String,
+	pub data: String,
 }
 
-impl Default for LLMModel {
-	fn default() -> LLMModel {
-		LLMModel::GPT4OMini
+impl ImageData {
+	pub async fn load(path: &Path) -> anyhow::Result<ImageData> {
+		let extension = path.extension().and_then(|e| e.to_str()).map(|e| e.to_lowercase());
+		let media_type = match extension.as_deref() {
+			Some("png") => "image/png",
+			Some("jpg") | Some("jpeg") => "image/jpeg",
+			_ => bail!("{} is not a PNG or JPEG image", path.display()),
+		};
+		let size = tokio::fs::metadata(path).await?.len();
+		if size > MAX_IMAGE_SIZE {
+			bail!("{} is too large ({} bytes), the limit is {} bytes", path.display(), size, MAX_IMAGE_SIZE);
+		}
+		let bytes = tokio::fs::read(path).await?;
+		Ok(ImageData {
+			media_type: media_type.to_string(),
+			data: BASE64_STANDARD.encode(bytes),
+		})
+	}
+
+	pub fn data_url(&self) -> String {
+		format!("data:{};base64,{}", self.media_type, self.data)
 	}
 }
 
-impl LLMModel {
-	pub fn input_cost(&self, token_count: u32) -> f32 {
-		match self {
-			LLMModel::GPT4O => token_count as f32 * GPT_40_INPUT_COST,
-			LLMModel::GPT4OMini => token_count as f32 * GPT_40_MINI_INPUT_COST,
-		}
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ContentPart {
+	Text { text: String },
+	Image(ImageData),
+}
+
+/// Text of the text parts, images are left out.
+pub fn parts_text(parts: &[ContentPart]) -> String {
+	parts.iter().filter_map(|part| match part {
+		ContentPart::Text { text } => Some(text.as_str()),
+		ContentPart::Image(_) => None,
+	}).collect::<Vec<_>>().join("\n")
+}
+
+/// User messages were plain strings before images could be attached.
+fn deserialize_user_content<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Vec<ContentPart>, D::Error> {
+	#[derive(Deserialize)]
+	#[serde(untagged)]
+	enum Repr {
+		Text(String),
+		Parts(Vec<ContentPart>),
 	}
+	Ok(match Repr::deserialize(deserializer)? {
+		Repr::Text(text) => vec![ContentPart::Text { text }],
+		Repr::Parts(parts) => parts,
+	})
+}
+
+/// Model identified by the id of the provider serving it and the model name
+/// understood by that provider.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(from = "LLMModelRepr")]
+pub struct LLMModel {
+	pub provider: String,
+	pub name: String,
+}
 
-	pub fn output_cost(&self, token_count: u32) -> f32 {
-		match self {
-			LLMModel::GPT4O => token_count as f32 * GPT_40_OUTPUT_COST,
-			LLMModel::GPT4OMini => token_count as f32 * GPT_40_MINI_OUTPUT_COST,
+/// Projects saved before providers were pluggable stored the model as a
+/// plain enum variant name.
+#[derive(serde::Deserialize)]
+#[serde(untagged)]
+enum LLMModelRepr {
+	Model { provider: String, name: String },
+	Legacy(String),
+}
+
+impl From<LLMModelRepr> for LLMModel {
+	fn from(repr: LLMModelRepr) -> LLMModel {
+		match repr {
+			LLMModelRepr::Model { provider, name } => LLMModel { provider, name },
+			LLMModelRepr::Legacy(variant) => match variant.as_str() {
+				"GPT4O" => LLMModel::new("openai", "gpt-4o"),
+				"Claude35Sonnet" => LLMModel::new("anthropic", "claude-3-5-sonnet-latest"),
+				"Claude35Haiku" => LLMModel::new("anthropic", "claude-3-5-haiku-latest"),
+				"Gemini15Pro" => LLMModel::new("gemini", "gemini-1.5-pro"),
+				"Gemini15Flash" => LLMModel::new("gemini", "gemini-1.5-flash"),
+				"Custom" => LLMModel::new(CUSTOM_PROVIDER, ""),
+				_ => LLMModel::default(),
+			},
 		}
 	}
+}
 
-	pub fn to_str(&self) -> &str {
-		match self {
-			LLMModel::GPT4O => GPT_4O,
-			LLMModel::GPT4OMini => GPT_4O_MINI,
-		}
+impl Default for LLMModel {
+	fn default() -> LLMModel {
+		LLMModel::new("openai", "gpt-4o-mini")
 	}
+}
 
-	pub fn from_str(s: &str) -> Option<LLMModel> {
-		match s {
-			GPT_4O => Some(LLMModel::GPT4O),
-			GPT_4O_MINI => Some(LLMModel::GPT4OMini),
-			_ => None,
+impl LLMModel {
+	pub fn new(provider: &str, name: &str) -> LLMModel {
+		LLMModel {
+			provider: provider.to_string(),
+			name: name.to_string(),
 		}
 	}
+
+	/// Unique key in `provider/name` form used for select options.
+	pub fn key(&self) -> String {
+		format!("{}/{}", self.provider, self.name)
+	}
+
+	pub fn from_key(s: &str) -> Option<LLMModel> {
+		let (provider, name) = s.split_once('/')?;
+		Some(LLMModel::new(provider, name))
+	}
 }
 
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub enum LLMMessage {
     System(String),
-    User(String),
+    User(#[serde(deserialize_with = "deserialize_user_content")] Vec<ContentPart>),
     Assistant(AssistantMsg),
     ToolResponse(ToolResponse),
 }
 
-#[derive(Debug)]
+/// Stands in for images sent to a model without vision.
+const IMAGE_OMITTED: &str = "[image omitted, the model can not see images]";
+
+impl LLMMessage {
+    #[cfg(test)]
+    pub fn user(text: &str) -> LLMMessage {
+        LLMMessage::User(vec![ContentPart::Text { text: text.to_string() }])
+    }
+
+    /// Replaces images with a note for models which can not see them.
+    pub fn without_images(self) -> LLMMessage {
+        match self {
+            LLMMessage::User(parts) => LLMMessage::User(parts.into_iter().map(|part| match part {
+                ContentPart::Image(_) => ContentPart::Text { text: IMAGE_OMITTED.to_string() },
+                part => part,
+            }).collect()),
+            LLMMessage::ToolResponse(mut res) if !res.images.is_empty() => {
+                res.images.clear();
+                res.content = format!("{}\n{}", res.content, IMAGE_OMITTED);
+                LLMMessage::ToolResponse(res)
+            }
+            msg => msg,
+        }
+    }
+}
+
+/// OpenAI compatible chat completions server such as Ollama, llama.cpp or vLLM.
+/// The model name is the free form `name` of the project's `LLMModel`.
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+pub struct CustomProvider {
+	/// Base url without the `/chat/completions` suffix, e.g. `http://localhost:11434/v1`.
+	pub base_url: String,
+	/// Name of the environment variable holding the api key. Empty means no auth.
+	pub api_key_env: String,
+}
+
+/// Sampling settings of a project. `None` leaves the setting to the
+/// provider default. Providers ignore the settings they do not support.
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct GenParams {
+	#[serde(default)]
+	pub temperature: Option<f32>,
+	#[serde(default)]
+	pub top_p: Option<f32>,
+	/// Maximum number of tokens in the response.
+	#[serde(default)]
+	pub max_tokens: Option<u32>,
+	#[serde(default)]
+	pub seed: Option<i64>,
+	#[serde(default)]
+	pub stop: Vec<String>,
+	#[serde(default)]
+	pub parallel_tool_calls: Option<bool>,
+	/// Only used by reasoning models.
+	#[serde(default)]
+	pub reasoning_effort: Option<ReasoningEffort>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReasoningEffort {
+	Low,
+	Medium,
+	High,
+}
+
+#[derive(Debug, Clone, Default)]
 pub struct GenRequest {
+    /// Project the request was made for. Results are tagged with it.
+    pub project_id: usize,
     pub model: LLMModel,
+    pub custom_provider: CustomProvider,
     pub messages: Vec<LLMMessage>,
     pub tools: Vec<Tool>,
+    pub params: GenParams,
+    /// The model is a reasoning model. Taken from the model catalog.
+    pub reasoning: bool,
+    /// Stream the response as deltas when the backend supports it.
+    pub stream: bool,
 }
 
 #[derive(Debug)]
@@ -99,58 +271,432 @@ pub struct ToolUse {
     pub args: String,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct SuccessfullGenResponse {
 	pub prompt_tokens: u32,
+	/// Part of `prompt_tokens` read from the provider's prompt cache.
+	pub cached_tokens: u32,
+	/// Includes the reasoning tokens.
 	pub completion_tokens: u32,
 	pub total_tokens: u32,
-	pub promt_cost: f32,
-	pub completion_cost: f32,
+	pub reasoning_tokens: u32,
+	pub promt_cost: MicroUsd,
+	pub completion_cost: MicroUsd,
     pub msg: AssistantMsg
 }
 
+/// Fragment of a tool call received while streaming. Calls are identified by
+/// `index`, the id and name only arrive with the first fragment.
+#[derive(Debug, Clone)]
+pub struct ToolCallDelta {
+	pub index: usize,
+	pub id: Option<String>,
+	pub name: Option<String>,
+	pub arguments: String,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct PartialToolCall {
+	pub id: String,
+	pub name: String,
+	pub arguments: String,
+}
+
+/// Assistant message assembled from stream deltas.
+#[derive(Debug, Clone, Default)]
+pub struct PartialAssistantMsg {
+	pub content: String,
+	pub tool_calls: Vec<PartialToolCall>,
+}
+
+impl PartialAssistantMsg {
+	pub fn push_content(&mut self, content: &str) {
+		self.content += content;
+	}
+
+	pub fn push_tool_call(&mut self, delta: &ToolCallDelta) {
+		while self.tool_calls.len() <= delta.index {
+			self.tool_calls.push(PartialToolCall::default());
+		}
+		let tool_call = &mut self.tool_calls[delta.index];
+		if let Some(id) = &delta.id {
+			tool_call.id = id.clone();
+		}
+		if let Some(name) = &delta.name {
+			tool_call.name += name;
+		}
+		tool_call.arguments += &delta.arguments;
+	}
+
+	pub fn finish(self) -> anyhow::Result<AssistantMsg> {
+		let mut tool_calls = vec![];
+		for tool_call in self.tool_calls {
+			tool_calls.push(ToolCall {
+				tool: ToolCallParameters::parse(&tool_call.name, &tool_call.arguments)?,
+				id: tool_call.id,
+				expanded: true,
+				waiting_permission: false,
+			});
+		}
+		Ok(AssistantMsg {
+			content: self.content,
+			tool_calls,
+		})
+	}
+}
+
 #[derive(Debug)]
 pub enum GenResult {
+    ContentDelta(String),
+    ToolCallDelta(ToolCallDelta),
+    /// Attempt failed with a transient error and will be retried after a delay.
+    Retrying(RetryInfo),
+    /// Final result with usage. Sent after all deltas of a streamed response.
     Response(SuccessfullGenResponse),
-    Error(String),
+    Error(LLMError),
+}
+
+struct GenTask {
+    seq: u64,
+    handle: JoinHandle<()>,
+}
+
+async fn gen_with_retries(
+	provider: Option<Arc<dyn LLMProvider>>,
+	req: &GenRequest,
+	retry_policy: &RetryPolicy,
+	gen_tx: &UnboundedSender<GenResult>,
+) -> anyhow::Result<SuccessfullGenResponse> {
+	let provider = match provider {
+		Some(provider) => provider,
+		None => return Err(LLMError::Other { message: format!("unknown provider {}", req.model.provider) }.into()),
+	};
+	let mut attempt = 1;
+	loop {
+		let err = match provider.gen(req.clone(), gen_tx).await {
+			Ok(res) => return Ok(res),
+			Err(err) => err,
+		};
+		match retry_policy.retry_delay(attempt, &err) {
+			Some(delay) => {
+				log::warn!("attempt {} failed, retrying in {:?}: {:?}", attempt, delay, err);
+				gen_tx.send(GenResult::Retrying(RetryInfo {
+					attempt,
+					max_attempts: retry_policy.max_attempts,
+					delay,
+					reason: err.to_string(),
+				})).unwrap();
+				tokio::time::sleep(delay).await;
+				attempt += 1;
+			}
+			None => return Err(err),
+		}
+	}
 }
 
 pub struct LLMClient {
-    client: reqwest::Client,
-    tx: UnboundedSender<GenResult>,
-    rx: UnboundedReceiver<GenResult>,
+    providers: ProviderRegistry,
+    catalog: ModelCatalog,
+    pub retry_policy: RetryPolicy,
+    cassette: Option<Arc<Cassette>>,
+    /// Request in flight per project.
+    tasks: HashMap<usize, GenTask>,
+    seq: u64,
+    tx: UnboundedSender<(usize, u64, GenResult)>,
+    rx: UnboundedReceiver<(usize, u64, GenResult)>,
 }
 
 impl LLMClient {
     pub fn new() -> LLMClient {
         let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        // No total timeout since streamed responses can take minutes.
+        let client = reqwest::Client::builder()
+            .connect_timeout(std::time::Duration::from_secs(10))
+            .read_timeout(std::time::Duration::from_secs(120))
+            .build()
+            .unwrap();
+
+        let mut providers = ProviderRegistry::default();
+        providers.register(Arc::new(OpenAIProvider::new(client.clone())));
+        providers.register(Arc::new(AnthropicProvider::new(client.clone())));
+        providers.register(Arc::new(GeminiProvider::new(client.clone())));
+        providers.register(Arc::new(CustomOpenAIProvider::new(client)));
+        let mut catalog = ModelCatalog::default();
+        catalog.add_defaults(&providers);
 
         LLMClient {
-            client: reqwest::Client::new(),
+            providers,
+            catalog,
+            retry_policy: RetryPolicy::default(),
+            cassette: None,
+            tasks: HashMap::new(),
+            seq: 0,
             tx,
             rx,
         }
     }
 
+    /// Records requests to the cassette or answers them from it, depending
+    /// on its mode.
+    pub fn set_cassette(&mut self, cassette: Cassette) {
+        self.cassette = Some(Arc::new(cassette));
+    }
+
+    pub fn register(&mut self, provider: Arc<dyn LLMProvider>) {
+        self.providers.register(provider);
+        self.catalog.add_defaults(&self.providers);
+    }
+
+    pub fn providers(&self) -> &ProviderRegistry {
+        &self.providers
+    }
+
+    /// Replaces the built in model list with the catalog file at `path`.
+    pub fn load_catalog(&mut self, path: &std::path::Path) {
+        if let Err(err) = self.catalog.load_or_create(path) {
+            log::error!("failed to load model catalog {:?}: {:?}", path, err);
+        }
+    }
+
+    /// Catalog models whose provider is registered.
+    pub fn models(&self) -> Vec<LLMModel> {
+        self.catalog.models().into_iter()
+            .filter(|model| self.providers.get(&model.provider).is_some())
+            .collect()
+    }
+
+    pub fn model_info(&self, model: &LLMModel) -> Option<ModelInfo> {
+        self.catalog.model_info(model)
+    }
+
+    /// Starts a request for `req.project_id`, cancelling the one the project
+    /// already has in flight.
     pub fn gen(&mut self, req: GenRequest) {
-        let client = self.client.clone();
+        let project_id = req.project_id;
+        self.cancel(project_id);
+        self.seq += 1;
+        let seq = self.seq;
         let tx = self.tx.clone();
-        tokio::spawn(async move {
-			let res = match req.model {
-				LLMModel::GPT4O | LLMModel::GPT4OMini => openai::gen(req, client).await,
+        let provider = self.providers.get(&req.model.provider);
+        let model_info = self.catalog.model_info(&req.model);
+        let retry_policy = self.retry_policy.clone();
+        let cassette = self.cassette.clone();
+        let handle = tokio::spawn(async move {
+			// Providers send untagged results, forward them with the project id.
+			let (gen_tx, mut gen_rx) = tokio::sync::mpsc::unbounded_channel();
+			let forward = async {
+				while let Some(res) = gen_rx.recv().await {
+					if tx.send((project_id, seq, res)).is_err() {
+						break;
+					}
+				}
 			};
+			let gen = async move {
+				let res = match &cassette {
+					Some(cassette) if cassette.mode() == VcrMode::Replay => cassette.find(&req),
+					_ => gen_with_retries(provider, &req, &retry_policy, &gen_tx).await,
+				};
+				if let Some(cassette) = cassette.filter(|c| c.mode() == VcrMode::Record) {
+					cassette.add(&req, &res);
+				}
 
-            match res {
-                Ok(res) => tx.send(GenResult::Response(res)).unwrap(),
-                Err(err) => {
-                    log::error!("gen failed: {:?}", err);
-                    tx.send(GenResult::Error(err.to_string())).unwrap();
-                }
-            };
+				match res {
+					Ok(mut res) => {
+						if let Some(info) = model_info {
+							res.promt_cost = info.prompt_cost(res.prompt_tokens, res.cached_tokens);
+							res.completion_cost = info.output_cost(res.completion_tokens);
+						}
+						gen_tx.send(GenResult::Response(res)).unwrap()
+					},
+					Err(err) => {
+						log::error!("gen failed: {:?}", err);
+						gen_tx.send(GenResult::Error(LLMError::classify(&err))).unwrap();
+					}
+				};
+			};
+			tokio::join!(gen, forward);
         });
+        self.tasks.insert(project_id, GenTask { seq, handle });
     }
 
-    pub async fn next(&mut self) -> Option<GenResult> {
-        self.rx.recv().await
+    pub fn is_running(&self, project_id: usize) -> bool {
+        self.tasks.get(&project_id).is_some_and(|task| !task.handle.is_finished())
     }
+
+    /// Aborts the request of the project. Results it already sent are
+    /// dropped. Returns false if there was nothing to cancel.
+    pub fn cancel(&mut self, project_id: usize) -> bool {
+        let task = match self.tasks.remove(&project_id) {
+            Some(task) => task,
+            None => return false,
+        };
+        let was_running = !task.handle.is_finished();
+        task.handle.abort();
+        was_running
+    }
+
+    /// Next result of any project, with the id of the project.
+    pub async fn next(&mut self) -> Option<(usize, GenResult)> {
+        loop {
+            let (project_id, seq, res) = self.rx.recv().await?;
+            // Skip results of cancelled or replaced requests.
+            if self.tasks.get(&project_id).is_some_and(|task| task.seq == seq) {
+                return Some((project_id, res));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_legacy_model_deserialization() {
+		let model: LLMModel = serde_json::from_str(r#""Claude35Sonnet""#).unwrap();
+		assert_eq!(model, LLMModel::new("anthropic", "claude-3-5-sonnet-latest"));
+
+		let model: LLMModel = serde_json::from_str(r#"{ "provider": "custom", "name": "llama3.1" }"#).unwrap();
+		assert_eq!(LLMModel::from_key(&model.key()), Some(model));
+
+		let msg: LLMMessage = serde_json::from_str(r#"{ "User": "Hello" }"#).unwrap();
+		assert!(matches!(msg, LLMMessage::User(parts) if parts == vec![ContentPart::Text { text: "Hello".to_string() }]));
+	}
+
+	#[tokio::test]
+	async fn test_rate_limited_request_is_retried() {
+		use wiremock::matchers::method;
+		use wiremock::Mock;
+		use wiremock::MockServer;
+		use wiremock::ResponseTemplate;
+
+		let server = MockServer::start().await;
+		Mock::given(method("POST"))
+			.respond_with(ResponseTemplate::new(429).insert_header("retry-after", "0"))
+			.up_to_n_times(1)
+			.mount(&server)
+			.await;
+		Mock::given(method("POST"))
+			.respond_with(ResponseTemplate::new(200).set_body_string(r#"{
+				"id": "chatcmpl-1",
+				"object": "chat.completion",
+				"created": 12412412,
+				"model": "qwen2.5-coder:7b",
+				"choices": [{
+					"index": 0,
+					"message": { "role": "assistant", "content": "finally" },
+					"finish_reason": "stop"
+				}]
+			}"#))
+			.mount(&server)
+			.await;
+
+		let mut client = LLMClient::new();
+		client.gen(GenRequest {
+			model: LLMModel::new(CUSTOM_PROVIDER, "qwen2.5-coder:7b"),
+			custom_provider: CustomProvider {
+				base_url: server.uri(),
+				api_key_env: "".to_string(),
+			},
+			messages: vec![LLMMessage::user("Hello")],
+			..Default::default()
+		});
+
+		match client.next().await.unwrap().1 {
+			GenResult::Retrying(info) => {
+				assert_eq!(info.attempt, 1);
+				assert_eq!(info.delay, std::time::Duration::ZERO);
+			}
+			res => panic!("unexpected result {:?}", res),
+		}
+		match client.next().await.unwrap().1 {
+			GenResult::Response(res) => assert_eq!(res.msg.content, "finally"),
+			res => panic!("unexpected result {:?}", res),
+		}
+	}
+
+	#[tokio::test]
+	async fn test_cancel_drops_request_in_flight() {
+		use wiremock::matchers::method;
+		use wiremock::Mock;
+		use wiremock::MockServer;
+		use wiremock::ResponseTemplate;
+
+		let server = MockServer::start().await;
+		Mock::given(method("POST"))
+			.respond_with(ResponseTemplate::new(500).set_delay(std::time::Duration::from_secs(5)))
+			.mount(&server)
+			.await;
+
+		let mut client = LLMClient::new();
+		client.gen(GenRequest {
+			model: LLMModel::new(CUSTOM_PROVIDER, "qwen2.5-coder:7b"),
+			custom_provider: CustomProvider {
+				base_url: server.uri(),
+				api_key_env: "".to_string(),
+			},
+			messages: vec![LLMMessage::user("Hello")],
+			..Default::default()
+		});
+		assert!(client.is_running(0));
+
+		assert!(client.cancel(0));
+		assert!(!client.is_running(0));
+		assert!(!client.cancel(0));
+		let res = tokio::time::timeout(std::time::Duration::from_millis(200), client.next()).await;
+		assert!(res.is_err(), "unexpected result {:?}", res);
+	}
+
+	#[tokio::test]
+	async fn test_results_are_tagged_with_project() {
+		use wiremock::matchers::body_partial_json;
+		use wiremock::matchers::method;
+		use wiremock::Mock;
+		use wiremock::MockServer;
+		use wiremock::ResponseTemplate;
+
+		let server = MockServer::start().await;
+		for name in ["slow", "fast"] {
+			let delay = if name == "slow" { 300 } else { 0 };
+			Mock::given(method("POST"))
+				.and(body_partial_json(serde_json::json!({ "model": name })))
+				.respond_with(ResponseTemplate::new(200)
+					.set_delay(std::time::Duration::from_millis(delay))
+					.set_body_string(format!(r#"{{
+						"id": "chatcmpl-1",
+						"object": "chat.completion",
+						"created": 12412412,
+						"model": "{}",
+						"choices": [{{
+							"index": 0,
+							"message": {{ "role": "assistant", "content": "{}" }},
+							"finish_reason": "stop"
+						}}]
+					}}"#, name, name)))
+				.mount(&server)
+				.await;
+		}
+
+		let mut client = LLMClient::new();
+		for (project_id, name) in [(1, "slow"), (2, "fast")] {
+			client.gen(GenRequest {
+				project_id,
+				model: LLMModel::new(CUSTOM_PROVIDER, name),
+				custom_provider: CustomProvider {
+					base_url: server.uri(),
+					api_key_env: "".to_string(),
+				},
+				messages: vec![LLMMessage::user("Hello")],
+				..Default::default()
+			});
+		}
+		assert!(client.is_running(1) && client.is_running(2));
+
+		for expected in [(2, "fast"), (1, "slow")] {
+			match client.next().await.unwrap() {
+				(project_id, GenResult::Response(res)) => assert_eq!((project_id, res.msg.content.as_str()), expected),
+				res => panic!("unexpected result {:?}", res),
+			}
+		}
+	}
 }
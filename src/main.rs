@@ -1,18 +1,51 @@
+use bash::Shell;
+use budget::BudgetScope;
 use env::load_envs;
 use generated::ToolCallParameters;
+use generated::Tool;
 use generated::TOOLS;
+use history::Fallback;
+use history::CANCELLED_MSG;
+use ledger::DisplayCurrency;
+use ledger::LedgerEntry;
+use ledger::MicroUsd;
 use llm::*;
+use mock::MockProvider;
+use vcr::Cassette;
+use tool::ToolContext;
+use tokenizer::fit_to_budget;
+use tokenizer::Encoding;
+use tokenizer::DEFAULT_CONTEXT_WINDOW;
+use tokenizer::RESERVED_OUTPUT_TOKENS;
+use tokenizer::TOKENS_PER_IMAGE;
+use tool::ToolRunner;
 use types::*;
 use ui::*;
+use utility::get_currency_path;
+use utility::get_model_catalog_path;
 use utility::get_projects_dir;
 use std::collections::HashSet;
 use std::fs::read_dir;
 use std::io;
 use std::net::TcpListener;
+use std::sync::Arc;
 use wgui::*;
 
 mod llm;
 mod openai;
+mod anthropic;
+mod gemini;
+mod provider;
+mod catalog;
+mod mock;
+mod vcr;
+mod retry;
+mod error;
+mod bash;
+mod process;
+mod budget;
+mod ledger;
+mod tokenizer;
 mod env;
 mod history;
 mod tool;
@@ -27,26 +60,54 @@ struct App {
 	clients: HashSet<usize>,
 	state: State,
 	llm_client: LLMClient,
+	tool_runner: ToolRunner,
 }
 
 impl App {
 	pub fn new(projects: Vec<Project>, port: u16) -> App {
-		let state = State {
-			projects,
+		let mut llm_client = LLMClient::new();
+		llm_client.load_catalog(&get_model_catalog_path());
+		// Registered after the catalog is loaded so its model is listed.
+		if let Some(mock) = MockProvider::from_env() {
+			llm_client.register(Arc::new(mock));
+		}
+		if let Some(cassette) = Cassette::from_env() {
+			llm_client.set_cassette(cassette);
+		}
+		let mut app = App::with_client(llm_client, projects, port);
+		app.state.currency = DisplayCurrency::load(&get_currency_path()).unwrap_or_else(|err| {
+			log::error!("failed to load currency: {:?}", err);
+			DisplayCurrency::default()
+		});
+		app
+	}
+
+	fn with_client(llm_client: LLMClient, projects: Vec<Project>, port: u16) -> App {
+		let mut state = State {
 			max_conversation_turns: 5,
 			max_context_size: 20,
+			models: llm_client.models(),
+			max_attempts: llm_client.retry_policy.max_attempts,
 			..Default::default()
 		};
+		for project in projects {
+			state.add_project(project);
+		}
 
 		App {
 			wgui: Wgui::new(format!("127.0.0.1:{}", port).parse().unwrap()),
 			clients: HashSet::new(),
 			state,
-			llm_client: LLMClient::new(),
+			llm_client,
+			tool_runner: ToolRunner::new(),
 		}
 	}
 
 	async fn render_ui(&mut self) {
+		for project in &mut self.state.projects {
+			project.conversation.running = self.llm_client.is_running(project.id) || self.tool_runner.is_running(project.id);
+		}
+		self.update_draft_estimate();
 		let item = ui(&self.state);
 
 		for client_id in &self.clients {
@@ -55,26 +116,42 @@ impl App {
 	}
 
 	fn send_message(&mut self) {
-		self.state.conversation_turns = 0;
 		let current_msg = self.state.current_msg.clone();
 		self.state.current_msg.clear();
+		let attachments = std::mem::take(&mut self.state.attachments);
 		let project = match self.get_active_project() {
 			Some(project) => project,
 			None => return,
 		};
+		project.conversation.turns = 0;
+		project.conversation.task_cost = MicroUsd::default();
+		project.conversation.budget_exceeded = None;
+		project.conversation.fallback = 0;
+		let mut parts = Vec::new();
 		if !current_msg.is_empty() {
-			project.history.add_message(LLMMessage::User(current_msg));
+			parts.push(ContentPart::Text { text: current_msg });
+		}
+		parts.extend(attachments.into_iter().map(|attachment| ContentPart::Image(attachment.image)));
+		if !parts.is_empty() {
+			project.history.add_message(LLMMessage::User(parts));
 			project.modified = true;
 		}
-		self.continue_conversation();
+		let project_id = project.id;
+		self.continue_conversation(project_id);
 	}
 
-	fn continue_conversation(&mut self) {
+	fn continue_conversation(&mut self, project_id: usize) {
 		let max_context_size = self.state.max_context_size;
-		let project = match self.get_active_project() {
+		let project = match self.state.project_mut(project_id) {
 			Some(project) => project,
 			None => return,
 		};
+		let model = project.active_model().clone();
+		let model_info = self.llm_client.model_info(&model);
+		// Models missing from the registry (e.g. custom endpoints) are assumed to support tools.
+		let supports_tools = model_info.as_ref().is_none_or(|info| info.tools);
+		let context_window = model_info.as_ref().map_or(DEFAULT_CONTEXT_WINDOW, |info| info.context_window);
+		let supports_vision = model_info.as_ref().is_none_or(|info| info.vision);
 		let mut messages = Vec::new();
 		let mut assistant_msg = String::new();
 		assistant_msg += r"You are puppycoder assistant 🐶\n 
@@ -92,24 +169,62 @@ You can use tools provided to you to read and write files.";
 			let msg = LLMMessage::System(project.instructions.clone());
 			messages.push(msg);
 		}
-		for _ in 0..max_context_size {
-			if let Some(item) = project.history.items.last() {
-				messages.push(item.content.clone());
-			} else {
-				break;
+		let context = project.history.get_context();
+		let skip = context.len().saturating_sub(max_context_size as usize);
+		for msg in context.into_iter().skip(skip) {
+			messages.push(if supports_vision { msg } else { msg.without_images() });
+		}
+		let tools: Vec<_> = TOOLS.iter()
+			.filter(|tool| supports_tools && project.activated_tools.contains(tool))
+			.filter(|tool| supports_vision || **tool != Tool::ViewImage)
+			.cloned().collect();
+
+		let encoding = Encoding::for_model(&model);
+		let reserved_output = project.gen_params.max_tokens.unwrap_or(RESERVED_OUTPUT_TOKENS);
+		let budget = context_window.saturating_sub(reserved_output + encoding.count_tools(&tools));
+		let budget = project.conversation.context_limit.map_or(budget, |limit| budget.min(limit));
+		let messages = fit_to_budget(encoding, messages, budget);
+		let token_count: u32 = messages.iter().map(|msg| encoding.count_message(msg)).sum();
+		log::info!("sending {} messages, {} tokens of {} budget", messages.len(), token_count, budget);
+		project.conversation.prompt_tokens = token_count;
+
+		// Only the prompt is known up front so the estimate leaves out the response.
+		let estimate = model_info.as_ref().map_or(MicroUsd::default(), |info| info.input_cost(token_count + encoding.count_tools(&tools)));
+		if !std::mem::take(&mut project.conversation.budget_override) {
+			if let Some(exceeded) = project.budget.check(&project.spending(), estimate.usd() as f32) {
+				log::warn!("request held back, {} limit {} would be exceeded", exceeded.scope.name(), exceeded.limit);
+				project.conversation.budget_exceeded = Some(exceeded);
+				return;
 			}
 		}
+		project.conversation.budget_exceeded = None;
+
 		let req = GenRequest {
-			model: project.model.clone(),
+			project_id,
+			model,
+			custom_provider: project.custom_provider.clone(),
 			messages,
-			tools: TOOLS.iter()
-				.filter(|tool| project.activated_tools.contains(tool))
-				.cloned().collect(),
+			tools,
+			params: project.gen_params.clone(),
+			reasoning: model_info.as_ref().is_some_and(|info| info.reasoning),
+			stream: true,
 		};
 
 		self.llm_client.gen(req);
 	}
 
+	/// Counts the tokens of the message being written for the active project's model.
+	fn update_draft_estimate(&mut self) {
+		let model = match self.state.active_project.and_then(|inx| self.state.projects.get(inx)) {
+			Some(project) => &project.model,
+			None => return,
+		};
+		let token_count = Encoding::for_model(model).count(&self.state.current_msg)
+			+ self.state.attachments.len() as u32 * TOKENS_PER_IMAGE;
+		self.state.draft_token_count = token_count;
+		self.state.draft_cost = self.llm_client.model_info(model).map_or(MicroUsd::default(), |info| info.input_cost(token_count));
+	}
+
 	fn get_active_project(&mut self) -> Option<&mut Project> {
 		let active_project = match self.state.active_project {
 			Some(inx) => inx,
@@ -135,6 +250,48 @@ You can use tools provided to you to read and write files.";
 					log::info!("Send message button clicked");
 					self.send_message();
 				}
+				STOP_BUTTON => {
+					log::info!("Stop button clicked");
+					self.stop();
+				}
+				RAISE_BUDGET_BUTTON => {
+					if let Some(project) = self.get_active_project() {
+						if let Some(exceeded) = project.conversation.budget_exceeded.take() {
+							*project.budget.limit_mut(exceeded.scope) = Some(exceeded.raised_limit());
+							project.modified = true;
+							let project_id = project.id;
+							self.continue_conversation(project_id);
+						}
+					}
+				}
+				CONTINUE_OVER_BUDGET_BUTTON => {
+					if let Some(project) = self.get_active_project() {
+						if project.conversation.budget_exceeded.take().is_some() {
+							project.conversation.budget_override = true;
+							let project_id = project.id;
+							self.continue_conversation(project_id);
+						}
+					}
+				}
+				RETRY_BUTTON => {
+					if let Some(project) = self.get_active_project() {
+						if !project.conversation.running {
+							let project_id = project.id;
+							self.continue_conversation(project_id);
+						}
+					}
+				}
+				COMPACT_CONTEXT_BUTTON => {
+					if let Some(project) = self.get_active_project() {
+						if !project.conversation.running {
+							let limit = project.conversation.prompt_tokens / 2;
+							project.conversation.context_limit = Some(limit);
+							log::info!("context compacted to {} tokens", limit);
+							let project_id = project.id;
+							self.continue_conversation(project_id);
+						}
+					}
+				}
 				TOOL_CHECKBOX => {
 					if let Some(project) = self.get_active_project() {
 						let inx = o.inx.unwrap() as usize;
@@ -154,6 +311,7 @@ You can use tools provided to you to read and write files.";
 							if let Some(project) = self.get_active_project() {
 								project.modified = true;
 								project.folder_path = handle.path().to_string_lossy().to_string();
+								project.shell = Arc::new(Shell::default());
 							}
 						},
 						None => {
@@ -161,12 +319,58 @@ You can use tools provided to you to read and write files.";
 						}
 					}
 				}
+				STOP_PROCESS_BUTTON => {
+					if let Some(project) = self.get_active_project() {
+						if let Some(info) = project.processes.list().get(o.inx.unwrap() as usize) {
+							if let Err(err) = project.processes.stop(&info.name) {
+								log::error!("failed to stop process {}: {:?}", info.name, err);
+							}
+						}
+					}
+				}
+				CLOSE_PROJECT_BUTTON => {
+					self.close_project();
+				}
+				RESTART_SHELL_BUTTON => {
+					// A hung command holds the old shell until its tool call is stopped.
+					if let Some(project_id) = self.get_active_project().map(|project| project.id) {
+						if self.tool_runner.is_running(project_id) {
+							self.stop();
+						}
+						if let Some(project) = self.state.project_mut(project_id) {
+							project.shell = Arc::new(Shell::default());
+						}
+					}
+				}
+				ATTACH_IMAGE_BUTTON => {
+					let file = rfd::AsyncFileDialog::new()
+						.add_filter("Image", &["png", "jpg", "jpeg"])
+						.pick_file().await;
+					match file {
+						Some(handle) => match ImageData::load(handle.path()).await {
+							Ok(image) => self.state.attachments.push(Attachment {
+								name: handle.file_name(),
+								image,
+							}),
+							Err(e) => log::error!("failed to attach image: {:?}", e),
+						},
+						None => {
+							log::info!("No image selected");
+						}
+					}
+				}
+				REMOVE_ATTACHMENT_BUTTON => {
+					let inx = o.inx.unwrap() as usize;
+					if inx < self.state.attachments.len() {
+						self.state.attachments.remove(inx);
+					}
+				}
 				NEW_PROJECT_BUTTON => {
 					let project = Project {
 						modified: true,
 						..Default::default()
 					};
-					self.state.projects.push(project);
+					self.state.add_project(project);
 				}
 				SAVE_PRJECT_BUTTON => {
 					if let Some(project) = self.get_active_project() {
@@ -189,6 +393,12 @@ You can use tools provided to you to read and write files.";
 						project.modified = true;
 					}
 				}
+				REMOVE_FALLBACK_MODEL_BUTTON => {
+					if let Some(project) = self.get_active_project() {
+						project.fallback_models.remove(o.inx.unwrap() as usize);
+						project.modified = true;
+					}
+				}
 				_ => {}
 			},
 			ClientEvent::OnTextChanged(t) => match t.id {
@@ -220,17 +430,140 @@ You can use tools provided to you to read and write files.";
 						self.state.max_context_size = t;
 					}
 				}
+				BASH_TIMEOUT_INPUT => {
+					if let Some(project) = self.get_active_project() {
+						project.bash_timeout_secs = t.value.trim().parse::<u64>().ok().filter(|secs| *secs > 0);
+						project.modified = true;
+					}
+				}
+				MAX_ATTEMPTS => {
+					if let Ok(t) = t.value.parse::<u32>() {
+						self.state.max_attempts = t.max(1);
+						self.llm_client.retry_policy.max_attempts = self.state.max_attempts;
+					}
+				}
+				CURRENCY_INPUT | EXCHANGE_RATE_INPUT => {
+					let currency = &mut self.state.currency;
+					if t.id == CURRENCY_INPUT {
+						currency.code = t.value.trim().to_uppercase();
+					} else if let Some(rate) = t.value.parse::<f64>().ok().filter(|rate| *rate > 0.0) {
+						currency.rate = rate;
+					}
+					if let Err(err) = currency.save(&get_currency_path()) {
+						log::error!("failed to save currency: {:?}", err);
+					}
+				}
+				PROJECT_LIMIT_INPUT | SESSION_LIMIT_INPUT | TASK_LIMIT_INPUT => {
+					let scope = match t.id {
+						PROJECT_LIMIT_INPUT => BudgetScope::Project,
+						SESSION_LIMIT_INPUT => BudgetScope::Session,
+						_ => BudgetScope::Task,
+					};
+					if let Some(project) = self.get_active_project() {
+						if let Some(limit) = parse_optional(&t.value) {
+							*project.budget.limit_mut(scope) = limit;
+							project.modified = true;
+						}
+					}
+				}
+				BUDGET_WARNING_INPUT => {
+					if let Some(project) = self.get_active_project() {
+						if let Ok(percent) = t.value.parse::<f32>() {
+							project.budget.warning_threshold = percent / 100.0;
+							project.modified = true;
+						}
+					}
+				}
+				TEMPERATURE_INPUT => {
+					if let Some(project) = self.get_active_project() {
+						if let Some(temperature) = parse_optional(&t.value) {
+							project.gen_params.temperature = temperature;
+							project.modified = true;
+						}
+					}
+				}
+				TOP_P_INPUT => {
+					if let Some(project) = self.get_active_project() {
+						if let Some(top_p) = parse_optional(&t.value) {
+							project.gen_params.top_p = top_p;
+							project.modified = true;
+						}
+					}
+				}
+				MAX_TOKENS_INPUT => {
+					if let Some(project) = self.get_active_project() {
+						if let Some(max_tokens) = parse_optional(&t.value) {
+							project.gen_params.max_tokens = max_tokens;
+							project.modified = true;
+						}
+					}
+				}
+				SEED_INPUT => {
+					if let Some(project) = self.get_active_project() {
+						if let Some(seed) = parse_optional(&t.value) {
+							project.gen_params.seed = seed;
+							project.modified = true;
+						}
+					}
+				}
+				STOP_SEQUENCES_INPUT => {
+					if let Some(project) = self.get_active_project() {
+						project.gen_params.stop = t.value.lines()
+							.filter(|line| !line.is_empty())
+							.map(|line| line.to_string())
+							.collect();
+						project.modified = true;
+					}
+				}
+				CUSTOM_BASE_URL_INPUT => {
+					if let Some(project) = self.get_active_project() {
+						project.custom_provider.base_url = t.value;
+						project.modified = true;
+					}
+				}
+				CUSTOM_API_KEY_ENV_INPUT => {
+					if let Some(project) = self.get_active_project() {
+						project.custom_provider.api_key_env = t.value;
+						project.modified = true;
+					}
+				}
+				CUSTOM_MODEL_INPUT => {
+					if let Some(project) = self.get_active_project() {
+						project.model = LLMModel::new(CUSTOM_PROVIDER, &t.value);
+						project.modified = true;
+					}
+				}
 				_ => {}
 			},
 			ClientEvent::OnSelect(event) => {
 				match event.id {
+					REASONING_EFFORT_SELECT => {
+						if let Some(project) = self.get_active_project() {
+							project.gen_params.reasoning_effort = serde_json::from_value(serde_json::Value::String(event.value)).ok();
+							project.modified = true;
+						}
+					}
+					PARALLEL_TOOL_CALLS_SELECT => {
+						if let Some(project) = self.get_active_project() {
+							project.gen_params.parallel_tool_calls = event.value.parse().ok();
+							project.modified = true;
+						}
+					}
 					MODEL_SELECT => {
 						log::info!("model selected: {:?}", event.value);
 						if let Some(project) = self.get_active_project() {
-							project.model = LLMModel::from_str(&event.value).unwrap();
+							project.model = LLMModel::from_key(&event.value).unwrap();
 							project.modified = true;
 						}
 					}
+					FALLBACK_MODEL_SELECT => {
+						if let Some(model) = LLMModel::from_key(&event.value) {
+							if let Some(project) = self.get_active_project() {
+								project.fallback_models.push(model);
+								project.modified = true;
+							}
+						}
+					}
 					_ => {}
 				}
 			}
@@ -240,64 +573,169 @@ You can use tools provided to you to read and write files.";
 		self.render_ui().await;
 	}
 
-	async fn handle_result(&mut self, result: GenResult) {
+	async fn handle_result(&mut self, project_id: usize, result: GenResult) {
+		let project = match self.state.project_mut(project_id) {
+			Some(project) => project,
+			None => return,
+		};
 		match result {
+			GenResult::ContentDelta(content) => {
+				project.conversation.streaming_msg.get_or_insert_with(Default::default).push_content(&content);
+			},
+			GenResult::ToolCallDelta(delta) => {
+				project.conversation.streaming_msg.get_or_insert_with(Default::default).push_tool_call(&delta);
+			},
+			GenResult::Retrying(info) => {
+				// A retried stream starts again from the beginning.
+				project.conversation.streaming_msg = None;
+				project.conversation.retrying = Some(info);
+			},
 			GenResult::Response(mut res) => {
 				log::info!("Response: {:?}", res);
-				if let Some(project) = self.get_active_project() {
-					let should_continue = res.msg.tool_calls.len() > 0;
-	
-					for tool_call in res.msg.tool_calls.iter_mut() {
-						let should_exec = match &tool_call.tool {
-							ToolCallParameters::ExecuteBashCmd(args) => {
-								
-								true
-							},
-							_ => false
-						};
-						if should_exec {
-							match tool::execute(&project, &tool_call.tool).await {
-								Ok(res) => {
-									log::info!("tool call result: {:?}", res);
-									project.history.add_message(LLMMessage::ToolResponse(ToolResponse { 
-										id: tool_call.id.clone(), 
-										content: res
-									}))
-								}
-								Err(e) => {
-									log::info!("tool call error: {:?}", e);
-									project.history.add_message(LLMMessage::ToolResponse(ToolResponse { 
-										id: tool_call.id.clone(), 
-										content: e.to_string()
-									}))
-								}
-							}
-						} else {
-							tool_call.waiting_permission = true;
-						}
+				project.conversation.streaming_msg = None;
+				project.conversation.retrying = None;
+				let should_continue = res.msg.tool_calls.len() > 0;
+				let mut tool_calls = vec![];
+
+				for tool_call in res.msg.tool_calls.iter_mut() {
+					let should_exec = match &tool_call.tool {
+						ToolCallParameters::ExecuteBashCmd(_) => true,
+						ToolCallParameters::ViewImage(_) => true,
+						_ => false
+					};
+					if should_exec {
+						tool_calls.push(tool_call.clone());
+					} else {
+						tool_call.waiting_permission = true;
 					}
+				}
 
-					project.history.add_message(LLMMessage::Assistant(res.msg.clone()));
-					project.input_token_count += res.prompt_tokens;
-					project.output_token_count += res.completion_tokens;
-					project.input_token_cost += res.promt_cost;
-					project.output_token_cost += res.completion_cost;
-					project.modified = true;
+				project.history.add_message(LLMMessage::Assistant(res.msg.clone()));
+				project.input_token_count += res.prompt_tokens;
+				project.cached_token_count += res.cached_tokens;
+				project.output_token_count += res.completion_tokens;
+				project.reasoning_token_count += res.reasoning_tokens;
+				// Billed to the model which answered, which can be a fallback.
+				let model = project.active_model().clone();
+				let info = self.llm_client.model_info(&model);
+				let entry = LedgerEntry {
+					timestamp: chrono::Utc::now(),
+					model,
+					price_version: info.as_ref().map(|info| info.price_version.clone()).unwrap_or_default(),
+					prompt_tokens: res.prompt_tokens,
+					cached_tokens: res.cached_tokens,
+					completion_tokens: res.completion_tokens,
+					reasoning_tokens: res.reasoning_tokens,
+					prompt_cost: res.promt_cost,
+					completion_cost: res.completion_cost,
+					cache_savings: info.as_ref().map_or(MicroUsd::default(), |info| {
+						info.input_cost(res.cached_tokens).saturating_sub(info.cached_input_cost(res.cached_tokens))
+					}),
+				};
+				project.conversation.session_cost += entry.cost();
+				project.conversation.task_cost += entry.cost();
+				project.ledger.add(entry);
+				project.modified = true;
 
-					if should_continue {
-						if self.state.conversation_turns < self.state.max_conversation_turns {
-							self.continue_conversation();
-						}
-						self.state.conversation_turns += 1;
-					}
+				// The conversation continues once the tools have finished.
+				if !tool_calls.is_empty() {
+					let ctx = ToolContext::from(&*project);
+					self.tool_runner.run(project_id, ctx, tool_calls);
+				} else if should_continue {
+					self.next_turn(project_id);
 				}
 			},
 			GenResult::Error(e) => {
 				log::info!("Error: {:?}", e);
+				project.conversation.streaming_msg = None;
+				project.conversation.retrying = None;
+				let from = project.active_model().clone();
+				let next = if e.can_fall_back() { project.fall_back().cloned() } else { None };
+				match next {
+					Some(to) => {
+						log::warn!("{} failed, falling back to {}", from.key(), to.key());
+						project.history.add_fallback(Fallback { from, to, error: e });
+						project.modified = true;
+						self.continue_conversation(project_id);
+					}
+					None => {
+						project.history.add_error(e);
+						project.modified = true;
+					}
+				}
 			},
 		}
 	}
 
+	fn handle_tool_responses(&mut self, project_id: usize, responses: Vec<ToolResponse>) {
+		if let Some(project) = self.state.project_mut(project_id) {
+			for res in responses {
+				project.history.add_message(LLMMessage::ToolResponse(res));
+			}
+			project.modified = true;
+			self.next_turn(project_id);
+		}
+	}
+
+	fn next_turn(&mut self, project_id: usize) {
+		let max_conversation_turns = self.state.max_conversation_turns;
+		let project = match self.state.project_mut(project_id) {
+			Some(project) => project,
+			None => return,
+		};
+		let turns = project.conversation.turns;
+		project.conversation.turns += 1;
+		if turns < max_conversation_turns {
+			self.continue_conversation(project_id);
+		}
+	}
+
+	/// Stops the running request and tools of the active project. Whatever
+	/// was cut off is recorded as cancelled so the history stays valid for
+	/// the next request.
+	fn stop(&mut self) {
+		let project = match self.state.active_project.and_then(|inx| self.state.projects.get_mut(inx)) {
+			Some(project) => project,
+			None => return,
+		};
+		let partial = project.conversation.streaming_msg.take();
+		project.conversation.retrying = None;
+		let gen_cancelled = self.llm_client.cancel(project.id);
+		let cancelled_tool_calls = self.tool_runner.cancel(project.id);
+		if gen_cancelled {
+			let content = match partial {
+				Some(msg) if !msg.content.is_empty() => format!("{}\n\n{}", msg.content, CANCELLED_MSG),
+				_ => CANCELLED_MSG.to_string(),
+			};
+			project.history.add_message(LLMMessage::Assistant(AssistantMsg {
+				content,
+				tool_calls: vec![],
+			}));
+			project.modified = true;
+		}
+		for id in cancelled_tool_calls {
+			project.history.add_message(LLMMessage::ToolResponse(ToolResponse {
+				id,
+				content: CANCELLED_MSG.to_string(),
+				images: Vec::new(),
+			}));
+			project.modified = true;
+		}
+	}
+
+	/// Removes the active project from the open projects, stopping whatever
+	/// it has running. Its file is left as it is.
+	fn close_project(&mut self) {
+		let inx = match self.state.active_project.take() {
+			Some(inx) if inx < self.state.projects.len() => inx,
+			_ => return,
+		};
+		let project = self.state.projects.remove(inx);
+		self.llm_client.cancel(project.id);
+		self.tool_runner.cancel(project.id);
+		project.processes.stop_all();
+	}
+
 	async fn run(mut self) {
 		loop {
 			tokio::select! {
@@ -313,11 +751,16 @@ You can use tools provided to you to read and write files.";
 						},
 					}
 				}
+				responses = self.tool_runner.next() => {
+					if let Some((project_id, responses)) = responses {
+						self.handle_tool_responses(project_id, responses);
+					}
+				}
 				result = self.llm_client.next() => {
 					match result {
-						Some(res) => {
-							log::info!("Result: {:?}", res);
-							self.handle_result(res).await;
+						Some((project_id, res)) => {
+							log::info!("Result for project {}: {:?}", project_id, res);
+							self.handle_result(project_id, res).await;
 						},
 						None => {
 							log::info!("No result");
@@ -331,6 +774,15 @@ You can use tools provided to you to read and write files.";
 	}
 }
 
+/// Parses an optional setting. An empty value clears the setting and an
+/// invalid one returns `None` so the old value is kept.
+fn parse_optional<T: std::str::FromStr>(value: &str) -> Option<Option<T>> {
+	match value.trim() {
+		"" => Some(None),
+		value => value.parse().ok().map(Some),
+	}
+}
+
 fn find_first_free_port(start_port: u16, end_port: u16) -> Option<u16> {
     for port in start_port..=end_port {
         match TcpListener::bind(("127.0.0.1", port)) {
@@ -369,7 +821,9 @@ async fn main() {
 			if path.is_file() {
 				log::info!("Loading project: {:?}", path);
 				let content = std::fs::read_to_string(path).unwrap();
-				Some(serde_json::from_str(&content).unwrap())
+				let mut project: Project = serde_json::from_str(&content).unwrap();
+				project.migrate_costs();
+				Some(project)
 			} else {
 				None
 			}
@@ -378,3 +832,141 @@ async fn main() {
 	let port = find_first_free_port(7760, 7780).unwrap();
 	App::new(projects, port).run().await;
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::time::Duration;
+	use error::LLMError;
+	use history::HistoryEntry;
+	use mock::MockScript;
+	use mock::MOCK_MODEL;
+	use mock::MOCK_PROVIDER;
+
+	/// Smallest valid PNG, a single transparent pixel.
+	const PIXEL_PNG: &str = "iVBORw0KGgoAAAANSUhEUgAAAAEAAAABCAYAAAAfFcSJAAAADUlEQVR42mNkYPhfDwAChwGA60e6kgAAAABJRU5ErkJggg==";
+
+	/// Handles results until the model answers without tool calls.
+	async fn run_until_answered(app: &mut App) {
+		let answered = |app: &App| matches!(
+			app.state.projects[0].history.items.last().map(|item| &item.content),
+			Some(HistoryEntry::Message(LLMMessage::Assistant(msg))) if msg.tool_calls.is_empty()
+		) || matches!(
+			app.state.projects[0].history.items.last().map(|item| &item.content),
+			Some(HistoryEntry::Error(_))
+		);
+		tokio::time::timeout(Duration::from_secs(5), async {
+			while !answered(app) {
+				tokio::select! {
+					Some((project_id, res)) = app.llm_client.next() => app.handle_result(project_id, res).await,
+					Some((project_id, responses)) = app.tool_runner.next() => app.handle_tool_responses(project_id, responses),
+				}
+			}
+		}).await.unwrap();
+	}
+
+	#[tokio::test]
+	async fn test_agent_loop_with_mock_provider() {
+		use base64::prelude::BASE64_STANDARD;
+		use base64::Engine;
+
+		let folder = std::env::temp_dir().join(format!("puppycoder-agent-{}", std::process::id()));
+		std::fs::create_dir_all(&folder).unwrap();
+		std::fs::write(folder.join("logo.png"), BASE64_STANDARD.decode(PIXEL_PNG).unwrap()).unwrap();
+
+		let script: MockScript = serde_json::from_str(r#"{
+			"steps": [
+				{
+					"expect": { "last_message_contains": "logo.png", "tools": ["view_image"] },
+					"content": "Let me look.",
+					"tool_calls": [{ "name": "view_image", "arguments": { "path": "logo.png" } }],
+					"prompt_tokens": 100,
+					"completion_tokens": 10
+				},
+				{
+					"expect": { "last_message_contains": "Image logo.png", "message_count": 3 },
+					"content": "It is a single transparent pixel.",
+					"prompt_tokens": 900,
+					"completion_tokens": 12
+				}
+			]
+		}"#).unwrap();
+		let mock = Arc::new(MockProvider::new(script));
+		let mut llm_client = LLMClient::new();
+		llm_client.register(mock.clone());
+		let project = Project {
+			model: LLMModel::new(MOCK_PROVIDER, MOCK_MODEL),
+			folder_path: folder.to_string_lossy().to_string(),
+			activated_tools: vec![Tool::ViewImage],
+			..Default::default()
+		};
+		let mut app = App::with_client(llm_client, vec![project], 0);
+		app.state.active_project = Some(0);
+		app.state.current_msg = "What is in logo.png?".to_string();
+
+		app.send_message();
+		run_until_answered(&mut app).await;
+
+		let requests = mock.requests();
+		assert_eq!(requests.len(), 2);
+		assert!(matches!(&requests[1].messages[2], LLMMessage::ToolResponse(res) if res.images.len() == 1));
+
+		let project = &app.state.projects[0];
+		assert_eq!(project.history.items.len(), 4);
+		assert_eq!(project.ledger.entries.len(), 2);
+		assert_eq!(project.input_token_count, 1000);
+
+		// The history survives saving and loading the project.
+		let saved: Project = serde_json::from_str(&serde_json::to_string(project).unwrap()).unwrap();
+		let context = saved.history.get_context();
+		assert_eq!(context.len(), 4);
+		assert!(matches!(&context[2], LLMMessage::ToolResponse(res) if res.images[0].media_type == "image/png"));
+		assert!(matches!(&context[3], LLMMessage::Assistant(msg) if msg.content == "It is a single transparent pixel."));
+		assert_eq!(saved.ledger.entries.len(), 2);
+
+		std::fs::remove_dir_all(&folder).unwrap();
+	}
+
+	#[tokio::test]
+	async fn test_fallback_chain() {
+		let script: MockScript = serde_json::from_str(r#"{
+			"steps": [
+				{ "expect": { "model": "scripted" }, "error": { "kind": "rate_limited", "message": "slow down" } },
+				{ "expect": { "model": "backup" }, "error": { "kind": "unavailable", "message": "overloaded" } },
+				{ "expect": { "model": "local" }, "content": "Hi", "prompt_tokens": 10, "completion_tokens": 2 },
+				{ "expect": { "model": "scripted" }, "error": { "kind": "auth", "env_var": null, "message": "bad key" } }
+			]
+		}"#).unwrap();
+		let mut llm_client = LLMClient::new();
+		llm_client.register(Arc::new(MockProvider::new(script)));
+		let project = Project {
+			model: LLMModel::new(MOCK_PROVIDER, MOCK_MODEL),
+			fallback_models: vec![LLMModel::new(MOCK_PROVIDER, "backup"), LLMModel::new(MOCK_PROVIDER, "local")],
+			..Default::default()
+		};
+		let mut app = App::with_client(llm_client, vec![project], 0);
+		app.state.active_project = Some(0);
+		app.state.current_msg = "Hello".to_string();
+
+		app.send_message();
+		run_until_answered(&mut app).await;
+		let project = &app.state.projects[0];
+		let reasons: Vec<_> = project.history.items.iter().filter_map(|item| match &item.content {
+			HistoryEntry::Fallback(fallback) => Some((fallback.to.name.as_str(), fallback.error.title())),
+			_ => None,
+		}).collect();
+		assert_eq!(reasons, [("backup", "Rate limited"), ("local", "Provider unavailable")]);
+		assert_eq!(project.ledger.entries[0].model.name, "local");
+		assert_eq!(project.history.get_context().len(), 2);
+
+		// A new message starts with the project's model again, and errors
+		// another model would not fix end the conversation.
+		app.state.current_msg = "Again".to_string();
+		app.send_message();
+		run_until_answered(&mut app).await;
+		assert!(matches!(
+			app.state.projects[0].history.items.last().map(|item| &item.content),
+			Some(HistoryEntry::Error(LLMError::Auth { .. }))
+		));
+	}
+}
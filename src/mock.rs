@@ -0,0 +1,250 @@
+use std::path::Path;
+use std::sync::Mutex;
+
+use async_trait::async_trait;
+use serde::Deserialize;
+use serde_json::Value;
+use tokio::sync::mpsc::UnboundedSender;
+
+use crate::error::LLMError;
+use crate::generated::ToolCallParameters;
+use crate::ledger::MicroUsd;
+use crate::llm;
+use crate::llm::parts_text;
+use crate::provider::LLMProvider;
+use crate::provider::ModelInfo;
+
+pub const MOCK_PROVIDER: &str = "mock";
+pub const MOCK_MODEL: &str = "scripted";
+/// Path of the script the mock provider is registered with when set.
+pub const MOCK_SCRIPT_ENV: &str = "PUPPYCODER_MOCK_SCRIPT";
+
+/// Responses the mock provider gives, one step per request in order.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct MockScript {
+	pub steps: Vec<MockStep>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct MockStep {
+	#[serde(default)]
+	pub expect: MockExpectation,
+	#[serde(default)]
+	pub content: String,
+	#[serde(default)]
+	pub tool_calls: Vec<MockToolCall>,
+	#[serde(default)]
+	pub prompt_tokens: u32,
+	#[serde(default)]
+	pub completion_tokens: u32,
+	/// Fails the request with this error instead of answering.
+	#[serde(default)]
+	pub error: Option<LLMError>,
+}
+
+/// Checks made on the request a step answers. A request which does not
+/// match fails like a provider error would.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct MockExpectation {
+	/// Name of the model the request is sent to.
+	pub model: Option<String>,
+	/// Text the newest message must contain.
+	pub last_message_contains: Option<String>,
+	pub message_count: Option<usize>,
+	/// Names of the tools sent with the request.
+	pub tools: Option<Vec<String>>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct MockToolCall {
+	/// Generated from the step number when left out.
+	pub id: Option<String>,
+	pub name: String,
+	#[serde(default)]
+	pub arguments: Value,
+}
+
+fn message_text(msg: &llm::LLMMessage) -> String {
+	match msg {
+		llm::LLMMessage::System(text) => text.clone(),
+		llm::LLMMessage::User(parts) => parts_text(parts),
+		llm::LLMMessage::Assistant(msg) => msg.content.clone(),
+		llm::LLMMessage::ToolResponse(res) => res.content.clone(),
+	}
+}
+
+impl MockExpectation {
+	fn check(&self, req: &llm::GenRequest) -> Result<(), String> {
+		if let Some(expected) = &self.model {
+			if req.model.name != *expected {
+				return Err(format!("expected model {:?}, got {:?}", expected, req.model.name));
+			}
+		}
+		if let Some(expected) = &self.last_message_contains {
+			let last = req.messages.last().map(message_text).unwrap_or_default();
+			if !last.contains(expected.as_str()) {
+				return Err(format!("expected the last message to contain {:?}, got {:?}", expected, last));
+			}
+		}
+		if let Some(expected) = self.message_count {
+			if req.messages.len() != expected {
+				return Err(format!("expected {} messages, got {}", expected, req.messages.len()));
+			}
+		}
+		if let Some(expected) = &self.tools {
+			let tools: Vec<&str> = req.tools.iter().map(|tool| tool.get_name()).collect();
+			if tools != *expected {
+				return Err(format!("expected tools {:?}, got {:?}", expected, tools));
+			}
+		}
+		Ok(())
+	}
+}
+
+/// Provider answering from a `MockScript` instead of a model, so the agent
+/// loop can be run in tests without network access. Every request is kept
+/// for assertions.
+pub struct MockProvider {
+	steps: Vec<MockStep>,
+	requests: Mutex<Vec<llm::GenRequest>>,
+}
+
+impl MockProvider {
+	pub fn new(script: MockScript) -> MockProvider {
+		MockProvider {
+			steps: script.steps,
+			requests: Mutex::new(Vec::new()),
+		}
+	}
+
+	pub fn load(path: &Path) -> anyhow::Result<MockProvider> {
+		let content = std::fs::read_to_string(path)?;
+		Ok(MockProvider::new(serde_json::from_str(&content)?))
+	}
+
+	/// Provider for the script in `PUPPYCODER_MOCK_SCRIPT`, if it is set.
+	pub fn from_env() -> Option<MockProvider> {
+		let path = std::env::var(MOCK_SCRIPT_ENV).ok()?;
+		match MockProvider::load(Path::new(&path)) {
+			Ok(provider) => Some(provider),
+			Err(err) => {
+				log::error!("failed to load mock script {}: {:?}", path, err);
+				None
+			}
+		}
+	}
+
+	pub fn requests(&self) -> Vec<llm::GenRequest> {
+		self.requests.lock().unwrap().clone()
+	}
+}
+
+#[async_trait]
+impl LLMProvider for MockProvider {
+	fn id(&self) -> &str {
+		MOCK_PROVIDER
+	}
+
+	fn models(&self) -> Vec<ModelInfo> {
+		vec![ModelInfo {
+			name: MOCK_MODEL.to_string(),
+			context_window: 128_000,
+			tools: true,
+			vision: true,
+			reasoning: false,
+			input_price: 0.0,
+			output_price: 0.0,
+			cached_input_price: 0.0,
+			price_version: String::new(),
+		}]
+	}
+
+	async fn gen(&self, req: llm::GenRequest, tx: &UnboundedSender<llm::GenResult>) -> anyhow::Result<llm::SuccessfullGenResponse> {
+		let inx = {
+			let mut requests = self.requests.lock().unwrap();
+			requests.push(req.clone());
+			requests.len() - 1
+		};
+		let step = match self.steps.get(inx) {
+			Some(step) => step,
+			None => return Err(LLMError::Other { message: format!("mock script has no step {}", inx + 1) }.into()),
+		};
+		if let Err(message) = step.expect.check(&req) {
+			return Err(LLMError::Other { message: format!("step {}: {}", inx + 1, message) }.into());
+		}
+		if let Some(error) = &step.error {
+			return Err(error.clone().into());
+		}
+
+		let mut tool_calls = Vec::new();
+		for (call_inx, call) in step.tool_calls.iter().enumerate() {
+			tool_calls.push(llm::ToolCall {
+				id: call.id.clone().unwrap_or_else(|| format!("call_{}_{}", inx + 1, call_inx + 1)),
+				expanded: true,
+				waiting_permission: false,
+				tool: ToolCallParameters::parse(&call.name, &call.arguments.to_string())?,
+			});
+		}
+		if req.stream && !step.content.is_empty() {
+			let _ = tx.send(llm::GenResult::ContentDelta(step.content.clone()));
+		}
+		Ok(llm::SuccessfullGenResponse {
+			prompt_tokens: step.prompt_tokens,
+			cached_tokens: 0,
+			completion_tokens: step.completion_tokens,
+			total_tokens: step.prompt_tokens + step.completion_tokens,
+			reasoning_tokens: 0,
+			promt_cost: MicroUsd::default(),
+			completion_cost: MicroUsd::default(),
+			msg: llm::AssistantMsg {
+				content: step.content.clone(),
+				tool_calls,
+			},
+		})
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[tokio::test]
+	async fn test_script() {
+		let script: MockScript = serde_json::from_str(r#"{
+			"steps": [
+				{
+					"expect": { "last_message_contains": "hello", "tools": [] },
+					"content": "Looking",
+					"tool_calls": [{ "name": "view_image", "arguments": { "path": "logo.png" } }]
+				}
+			]
+		}"#).unwrap();
+		let provider = MockProvider::new(script);
+		let (tx, _rx) = tokio::sync::mpsc::unbounded_channel();
+
+		let req = llm::GenRequest {
+			messages: vec![llm::LLMMessage::user("well hello there")],
+			..Default::default()
+		};
+		let res = provider.gen(req.clone(), &tx).await.unwrap();
+		assert_eq!(res.msg.content, "Looking");
+		assert_eq!(res.msg.tool_calls[0].id, "call_1_1");
+		assert!(matches!(&res.msg.tool_calls[0].tool, ToolCallParameters::ViewImage(args) if args.path == "logo.png"));
+
+		// Out of steps.
+		assert!(provider.gen(req, &tx).await.is_err());
+		assert_eq!(provider.requests().len(), 2);
+
+		let provider = MockProvider::new(MockScript {
+			steps: vec![MockStep {
+				expect: MockExpectation {
+					message_count: Some(3),
+					..Default::default()
+				},
+				..Default::default()
+			}],
+		});
+		let err = provider.gen(llm::GenRequest::default(), &tx).await.unwrap_err();
+		assert_eq!(err.to_string(), "Request failed: step 1: expected 3 messages, got 0");
+	}
+}
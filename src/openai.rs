@@ -1,12 +1,20 @@
 use std::default;
 
 use anyhow::bail;
+use async_trait::async_trait;
 use reqwest::Client;
 use reqwest::StatusCode;
 use serde::Deserialize;
 use serde::Serialize;
 use serde_json::Value;
+use tokio::sync::mpsc::UnboundedSender;
+use crate::error::LLMError;
+use crate::ledger::MicroUsd;
 use crate::llm;
+use crate::provider::LLMProvider;
+use crate::provider::ModelInfo;
+use crate::provider::PRICE_VERSION;
+use crate::retry::HttpError;
 use crate::tool;
 
 #[derive(Deserialize, Debug)]
@@ -16,20 +24,24 @@ struct ChatCompletion {
     created: i64,
     model: String,
     choices: Vec<Choice>,
+    #[serde(default)]
     usage: Usage,
-    system_fingerprint: String,
+    system_fingerprint: Option<String>,
     service_tier: Option<String>,
 }
 
 impl ChatCompletion {
-	fn to_successfull_gen_response(&self, model: &llm::LLMModel) -> anyhow::Result<llm::SuccessfullGenResponse> {
-		let first_choice = self.choices.first().ok_or_else(|| anyhow::anyhow!("no choices"))?;
+	fn to_successfull_gen_response(&self) -> anyhow::Result<llm::SuccessfullGenResponse> {
+		let first_choice = self.choices.first().ok_or_else(|| LLMError::malformed("no choices"))?;
+		if first_choice.finish_reason == CONTENT_FILTER {
+			return Err(content_filtered().into());
+		}
 
 		let res = llm::SuccessfullGenResponse {
-			promt_cost: model.input_cost(self.usage.prompt_tokens),
-			completion_cost: model.output_cost(self.usage.completion_tokens),
+			promt_cost: MicroUsd::default(),
+			completion_cost: MicroUsd::default(),
 			msg: llm::AssistantMsg {
-				content: first_choice.message.content.clone().unwrap_or_else(|| "".to_string()),
+				content: first_choice.message.content.as_ref().map(|content| content.text()).unwrap_or_default(),
 				tool_calls: match &first_choice.message.tool_calls {
 					Some(tool_calls) => tool_calls.iter().filter_map(|tool_call| {
 						Some(llm::ToolCall {
@@ -43,8 +55,10 @@ impl ChatCompletion {
 				},
 			},
 			prompt_tokens: self.usage.prompt_tokens,
+			cached_tokens: self.usage.cached_tokens(),
 			completion_tokens: self.usage.completion_tokens,
 			total_tokens: self.usage.total_tokens,
+			reasoning_tokens: self.usage.reasoning_tokens(),
 		};
 
 		Ok(res)
@@ -62,12 +76,50 @@ struct Choice {
 #[derive(Serialize, Deserialize, Debug)]
 struct OAIMessage {
     role: String,
-    content: Option<String>,
+    content: Option<OAIContent>,
 	tool_calls: Option<Vec<ToolCall>>,
     refusal: Option<String>,
 	tool_call_id: Option<String>,
 }
 
+/// Plain text, or text and images as separate parts.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(untagged)]
+enum OAIContent {
+	Text(String),
+	Parts(Vec<OAIContentPart>),
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum OAIContentPart {
+	Text { text: String },
+	ImageUrl { image_url: ImageUrl },
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct ImageUrl {
+	url: String,
+}
+
+impl OAIContent {
+	fn text(&self) -> String {
+		match self {
+			OAIContent::Text(text) => text.clone(),
+			OAIContent::Parts(parts) => parts.iter().filter_map(|part| match part {
+				OAIContentPart::Text { text } => Some(text.as_str()),
+				OAIContentPart::ImageUrl { .. } => None,
+			}).collect(),
+		}
+	}
+}
+
+impl From<&llm::ImageData> for OAIContentPart {
+	fn from(image: &llm::ImageData) -> OAIContentPart {
+		OAIContentPart::ImageUrl { image_url: ImageUrl { url: image.data_url() } }
+	}
+}
+
 #[derive(Deserialize, Serialize, Debug)]
 struct ToolCall {
     id: String,
@@ -139,11 +191,37 @@ struct LogprobRefusal {
     // Define fields for log probability of refusal tokens
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, Default)]
+struct CompletionTokensDetails {
+    #[serde(default)]
+    reasoning_tokens: u32,
+}
+
+#[derive(Deserialize, Debug, Default)]
+struct PromptTokensDetails {
+    #[serde(default)]
+    cached_tokens: u32,
+}
+
+#[derive(Deserialize, Debug, Default)]
 struct Usage {
     prompt_tokens: u32,
     completion_tokens: u32,
     total_tokens: u32,
+    #[serde(default)]
+    prompt_tokens_details: Option<PromptTokensDetails>,
+    #[serde(default)]
+    completion_tokens_details: Option<CompletionTokensDetails>,
+}
+
+impl Usage {
+    fn cached_tokens(&self) -> u32 {
+        self.prompt_tokens_details.as_ref().map_or(0, |d| d.cached_tokens)
+    }
+
+    fn reasoning_tokens(&self) -> u32 {
+        self.completion_tokens_details.as_ref().map_or(0, |d| d.reasoning_tokens)
+    }
 }
 
 // struct Tool {
@@ -156,12 +234,240 @@ struct Usage {
 struct OAIRequest {
 	messages: Vec<OAIMessage>,
 	model: String,
-	tools: Vec<Value>
+	tools: Vec<Value>,
+	#[serde(skip_serializing_if = "std::ops::Not::not")]
+	stream: bool,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	stream_options: Option<Value>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	temperature: Option<f32>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	top_p: Option<f32>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	max_tokens: Option<u32>,
+	/// Replaces `max_tokens` for reasoning models since it also limits reasoning tokens.
+	#[serde(skip_serializing_if = "Option::is_none")]
+	max_completion_tokens: Option<u32>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	reasoning_effort: Option<llm::ReasoningEffort>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	seed: Option<i64>,
+	#[serde(skip_serializing_if = "Vec::is_empty")]
+	stop: Vec<String>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	parallel_tool_calls: Option<bool>,
+}
+
+#[derive(Deserialize, Debug)]
+struct ChunkFunction {
+	name: Option<String>,
+	arguments: Option<String>,
 }
 
-pub async fn gen(req: llm::GenRequest, client: Client) -> anyhow::Result<llm::SuccessfullGenResponse> {
+#[derive(Deserialize, Debug)]
+struct ChunkToolCall {
+	index: usize,
+	id: Option<String>,
+	function: Option<ChunkFunction>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Delta {
+	content: Option<String>,
+	tool_calls: Option<Vec<ChunkToolCall>>,
+}
+
+#[derive(Deserialize, Debug)]
+struct ChunkChoice {
+	delta: Delta,
+	#[serde(default)]
+	finish_reason: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct ChatCompletionChunk {
+	#[serde(default)]
+	choices: Vec<ChunkChoice>,
+	usage: Option<Usage>,
+}
+
+/// Splits a server sent events byte stream into `data:` payloads. Bytes are
+/// buffered until a full line arrives so multibyte characters split between
+/// network chunks stay intact.
+#[derive(Default)]
+struct SseParser {
+	buf: Vec<u8>,
+}
+
+impl SseParser {
+	fn push(&mut self, bytes: &[u8]) -> Vec<String> {
+		self.buf.extend_from_slice(bytes);
+		let mut payloads = vec![];
+		while let Some(pos) = self.buf.iter().position(|b| *b == b'\n') {
+			let line: Vec<u8> = self.buf.drain(..=pos).collect();
+			let line = String::from_utf8_lossy(&line);
+			if let Some(data) = line.trim_end().strip_prefix("data:") {
+				payloads.push(data.trim_start().to_string());
+			}
+		}
+		payloads
+	}
+}
+
+async fn read_stream(mut res: reqwest::Response, tx: &UnboundedSender<llm::GenResult>) -> anyhow::Result<llm::SuccessfullGenResponse> {
+	let mut parser = SseParser::default();
+	let mut msg = llm::PartialAssistantMsg::default();
+	let mut usage = Usage::default();
+
+	'outer: while let Some(bytes) = res.chunk().await? {
+		for data in parser.push(&bytes) {
+			if data == "[DONE]" {
+				break 'outer;
+			}
+			let chunk: ChatCompletionChunk = serde_json::from_str(&data)?;
+			if let Some(u) = chunk.usage {
+				usage = u;
+			}
+			for choice in chunk.choices {
+				if choice.finish_reason.as_deref() == Some(CONTENT_FILTER) {
+					return Err(content_filtered().into());
+				}
+				if let Some(content) = choice.delta.content {
+					if !content.is_empty() {
+						msg.push_content(&content);
+						let _ = tx.send(llm::GenResult::ContentDelta(content));
+					}
+				}
+				for tool_call in choice.delta.tool_calls.unwrap_or_default() {
+					let (name, arguments) = match tool_call.function {
+						Some(f) => (f.name, f.arguments.unwrap_or_default()),
+						None => (None, String::new()),
+					};
+					let delta = llm::ToolCallDelta {
+						index: tool_call.index,
+						id: tool_call.id,
+						name,
+						arguments,
+					};
+					msg.push_tool_call(&delta);
+					let _ = tx.send(llm::GenResult::ToolCallDelta(delta));
+				}
+			}
+		}
+	}
+
+	Ok(llm::SuccessfullGenResponse {
+		promt_cost: MicroUsd::default(),
+		completion_cost: MicroUsd::default(),
+		prompt_tokens: usage.prompt_tokens,
+		cached_tokens: usage.cached_tokens(),
+		completion_tokens: usage.completion_tokens,
+		total_tokens: usage.total_tokens,
+		reasoning_tokens: usage.reasoning_tokens(),
+		msg: msg.finish()?,
+	})
+}
+
+const OPENAI_API_URL: &str = "https://api.openai.com/v1";
+/// Finish reason of a response cut off by moderation.
+const CONTENT_FILTER: &str = "content_filter";
+pub const GPT_4O: &str = "gpt-4o";
+pub const GPT_4O_MINI: &str = "gpt-4o-mini";
+pub const O1: &str = "o1";
+pub const O3_MINI: &str = "o3-mini";
+
+fn content_filtered() -> LLMError {
+	LLMError::ContentFiltered { message: "the response was stopped by the content filter".to_string() }
+}
+
+/// Tool messages only take text so images returned by tools are sent in a
+/// user message after the tool responses.
+fn to_oai_messages(req: &llm::GenRequest) -> Vec<OAIMessage> {
+	let mut messages = Vec::new();
+	let mut tool_images: Vec<OAIContentPart> = Vec::new();
+	for msg in &req.messages {
+		if !matches!(msg, llm::LLMMessage::ToolResponse(_)) && !tool_images.is_empty() {
+			messages.push(user_message(OAIContent::Parts(std::mem::take(&mut tool_images))));
+		}
+		messages.push(match msg {
+			llm::LLMMessage::Assistant(msg) => OAIMessage {
+				role: "assistant".to_string(),
+				content: Some(OAIContent::Text(msg.content.clone())),
+				tool_calls: if msg.tool_calls.len() > 0 {
+					Some(msg.tool_calls.iter().map(|tool_call| {
+						ToolCall {
+							id: tool_call.id.clone(),
+							type_: "function".to_string(),
+							function: Function {
+								name: tool_call.tool.get_name().to_string(),
+								arguments: tool_call.tool.get_args(),
+							}
+						}
+					}).collect())
+				} else {
+					None
+				},
+				refusal: None,
+				tool_call_id: None,
+			},
+			llm::LLMMessage::User(parts) => user_message(match parts.as_slice() {
+				[llm::ContentPart::Text { text }] => OAIContent::Text(text.clone()),
+				parts => OAIContent::Parts(parts.iter().map(|part| match part {
+					llm::ContentPart::Text { text } => OAIContentPart::Text { text: text.clone() },
+					llm::ContentPart::Image(image) => image.into(),
+				}).collect()),
+			}),
+			llm::LLMMessage::System(msg) => OAIMessage {
+				// Reasoning models take instructions with the developer role.
+				role: if req.reasoning { "developer" } else { "system" }.to_string(),
+				content: Some(OAIContent::Text(msg.clone())),
+				tool_calls: None,
+				refusal: None,
+				tool_call_id: None,
+			},
+			llm::LLMMessage::ToolResponse(r) => {
+				tool_images.extend(r.images.iter().map(OAIContentPart::from));
+				OAIMessage {
+					role: "tool".to_string(),
+					content: Some(OAIContent::Text(r.content.clone())),
+					tool_calls: None,
+					refusal: None,
+					tool_call_id: Some(r.id.clone()),
+				}
+			},
+		});
+	}
+	if !tool_images.is_empty() {
+		messages.push(user_message(OAIContent::Parts(tool_images)));
+	}
+	messages
+}
+
+fn user_message(content: OAIContent) -> OAIMessage {
+	OAIMessage {
+		role: "user".to_string(),
+		content: Some(content),
+		tool_calls: None,
+		refusal: None,
+		tool_call_id: None,
+	}
+}
+
+async fn send(req: llm::GenRequest, client: &Client, base_url: &str, apikey: Option<&str>, tx: &UnboundedSender<llm::GenResult>) -> anyhow::Result<llm::SuccessfullGenResponse> {
 	let oaireq = OAIRequest {
-		model: req.model.to_str().to_string(),
+		model: req.model.name.clone(),
+		stream: req.stream,
+		stream_options: if req.stream { Some(serde_json::json!({ "include_usage": true })) } else { None },
+		// Reasoning models reject sampling parameters.
+		temperature: req.params.temperature.filter(|_| !req.reasoning),
+		top_p: req.params.top_p.filter(|_| !req.reasoning),
+		max_tokens: req.params.max_tokens.filter(|_| !req.reasoning),
+		max_completion_tokens: req.params.max_tokens.filter(|_| req.reasoning),
+		reasoning_effort: req.params.reasoning_effort.filter(|_| req.reasoning),
+		seed: req.params.seed,
+		stop: req.params.stop.clone(),
+		// Only allowed together with tools.
+		parallel_tool_calls: req.params.parallel_tool_calls.filter(|_| !req.tools.is_empty()),
 		tools: req.tools.iter().map(|p| serde_json::json!(
 			{
 				"type": "function",
@@ -172,86 +478,161 @@ pub async fn gen(req: llm::GenRequest, client: Client) -> anyhow::Result<llm::Su
 				}
 			}
 		)).collect(),
-		messages: req.messages.iter().map(|msg| {
-			match msg {
-				llm::LLMMessage::Assistant(msg) => OAIMessage {
-					role: "assistant".to_string(),
-					content: Some(msg.content.clone()),
-					tool_calls: if msg.tool_calls.len() > 0 {
-						Some(msg.tool_calls.iter().map(|tool_call| {
-							ToolCall {
-								id: tool_call.id.clone(),
-								type_: "function".to_string(),
-								function: Function {
-									name: tool_call.tool.get_name().to_string(),
-									arguments: tool_call.tool.get_args(),
-								}
-							}
-						}).collect())
-					} else {
-						None
-					},
-					refusal: None,
-					tool_call_id: None,
-				},
-				llm::LLMMessage::User(msg) => OAIMessage {
-					role: "user".to_string(),
-					content: Some(msg.clone()),
-					tool_calls: None,
-					refusal: None,
-					tool_call_id: None,
-				},
-				llm::LLMMessage::System(msg) => OAIMessage {
-					role: "system".to_string(),
-					content: Some(msg.clone()),
-					tool_calls: None,
-					refusal: None,
-					tool_call_id: None,
-				},
-				llm::LLMMessage::ToolResponse(r) => OAIMessage {
-					role: "tool".to_string(),
-					content: Some(r.content.clone()),
-					tool_calls: None,
-					refusal: None,
-					tool_call_id: Some(r.id.clone()),
-				},
-			}
-		}).collect(),
+		messages: to_oai_messages(&req),
 	};
 
 	let body = serde_json::to_string(&oaireq)?;
-    let apikey = match std::env::var("OPENAI_API_KEY") {
-		Ok(key) => key,
-		Err(_) => {
-			bail!("OPENAI_API_KEY not set");
-		}
-	};
 
 	log::info!("body: {}", body);
 
-    let res = client
-        .post("https://api.openai.com/v1/chat/completions")
-        .header("Authorization", format!("Bearer {}", apikey))
-        .header("Content-Type", "application/json")
+    let mut builder = client
+        .post(format!("{}/chat/completions", base_url.trim_end_matches('/')))
+        .header("Content-Type", "application/json");
+    if let Some(apikey) = apikey {
+        builder = builder.header("Authorization", format!("Bearer {}", apikey));
+    }
+    let res = builder
         .body(body)
         .send()
         .await?;
 
     let status_code = res.status();
+	if status_code == StatusCode::OK && req.stream {
+		return read_stream(res, tx).await;
+	}
+	let headers = res.headers().clone();
 	let text = res.text().await?;
 
 	if status_code != StatusCode::OK {
 		log::error!("request failed with code {}", status_code);
 		log::error!("response: {}", text);
-		bail!("request failed with code {}", status_code);
+		return Err(HttpError::new(status_code, &headers, text).into());
 	}
 
 	log::info!("response: {}", text);
     let chat_completion: ChatCompletion = serde_json::from_str(&text)?;
-	let res = chat_completion.to_successfull_gen_response(&req.model)?;
+	let res = chat_completion.to_successfull_gen_response()?;
     Ok(res)
 }
 
+pub struct OpenAIProvider {
+	client: Client,
+}
+
+impl OpenAIProvider {
+	pub fn new(client: Client) -> OpenAIProvider {
+		OpenAIProvider { client }
+	}
+}
+
+#[async_trait]
+impl LLMProvider for OpenAIProvider {
+	fn id(&self) -> &str {
+		"openai"
+	}
+
+	fn models(&self) -> Vec<ModelInfo> {
+		vec![
+			ModelInfo {
+				name: GPT_4O_MINI.to_string(),
+				context_window: 128_000,
+				tools: true,
+				vision: true,
+				reasoning: false,
+				input_price: 0.150,
+				output_price: 0.600,
+				cached_input_price: 0.075,
+				price_version: PRICE_VERSION.to_string(),
+			},
+			ModelInfo {
+				name: GPT_4O.to_string(),
+				context_window: 128_000,
+				tools: true,
+				vision: true,
+				reasoning: false,
+				input_price: 2.5,
+				output_price: 10.0,
+				cached_input_price: 1.25,
+				price_version: PRICE_VERSION.to_string(),
+			},
+			ModelInfo {
+				name: O1.to_string(),
+				context_window: 200_000,
+				tools: true,
+				vision: true,
+				reasoning: true,
+				input_price: 15.0,
+				output_price: 60.0,
+				cached_input_price: 7.5,
+				price_version: PRICE_VERSION.to_string(),
+			},
+			ModelInfo {
+				name: O3_MINI.to_string(),
+				context_window: 200_000,
+				tools: true,
+				vision: false,
+				reasoning: true,
+				input_price: 1.1,
+				output_price: 4.4,
+				cached_input_price: 0.55,
+				price_version: PRICE_VERSION.to_string(),
+			},
+		]
+	}
+
+	async fn gen(&self, req: llm::GenRequest, tx: &UnboundedSender<llm::GenResult>) -> anyhow::Result<llm::SuccessfullGenResponse> {
+		let apikey = match std::env::var("OPENAI_API_KEY") {
+			Ok(key) => key,
+			Err(_) => {
+				return Err(LLMError::auth_missing("OPENAI_API_KEY").into());
+			}
+		};
+		send(req, &self.client, OPENAI_API_URL, Some(&apikey), tx).await
+	}
+}
+
+/// Talks to the OpenAI compatible server configured in the request's `CustomProvider`.
+pub struct CustomOpenAIProvider {
+	client: Client,
+}
+
+impl CustomOpenAIProvider {
+	pub fn new(client: Client) -> CustomOpenAIProvider {
+		CustomOpenAIProvider { client }
+	}
+}
+
+#[async_trait]
+impl LLMProvider for CustomOpenAIProvider {
+	fn id(&self) -> &str {
+		llm::CUSTOM_PROVIDER
+	}
+
+	fn models(&self) -> Vec<ModelInfo> {
+		vec![]
+	}
+
+	async fn gen(&self, req: llm::GenRequest, tx: &UnboundedSender<llm::GenResult>) -> anyhow::Result<llm::SuccessfullGenResponse> {
+		let base_url = req.custom_provider.base_url.clone();
+		let apikey_env = req.custom_provider.api_key_env.clone();
+		if base_url.is_empty() {
+			bail!("custom provider base url not set");
+		}
+		// Local servers usually run without auth so the key is optional for them.
+		let apikey = if apikey_env.is_empty() {
+			None
+		} else {
+			match std::env::var(&apikey_env) {
+				Ok(key) => Some(key),
+				Err(_) => {
+					return Err(LLMError::auth_missing(&apikey_env).into());
+				}
+			}
+		};
+		send(req, &self.client, &base_url, apikey.as_deref(), tx).await
+	}
+}
+
 #[cfg(test)]
 mod tests {
 	use super::*;
@@ -296,8 +677,207 @@ mod tests {
 		let res = serde_json::from_str::<ChatCompletion>(str).unwrap();
 		println!("{:?}", res);
 
-		let result = res.to_successfull_gen_response(&llm::LLMModel::GPT4OMini).unwrap();
+		let result = res.to_successfull_gen_response().unwrap();
 
 		println!("{:?}", result);
 	}
+
+	#[tokio::test]
+	async fn test_custom_provider() {
+		use wiremock::matchers::body_partial_json;
+		use wiremock::matchers::method;
+		use wiremock::matchers::path;
+		use wiremock::Mock;
+		use wiremock::MockServer;
+		use wiremock::ResponseTemplate;
+
+		let server = MockServer::start().await;
+		Mock::given(method("POST"))
+			.and(path("/v1/chat/completions"))
+			.and(body_partial_json(serde_json::json!({ "model": "qwen2.5-coder:7b" })))
+			.respond_with(ResponseTemplate::new(200).set_body_string(r#"{
+				"id": "chatcmpl-1",
+				"object": "chat.completion",
+				"created": 12412412,
+				"model": "qwen2.5-coder:7b",
+				"choices": [{
+					"index": 0,
+					"message": { "role": "assistant", "content": "Hello from ollama" },
+					"finish_reason": "stop"
+				}],
+				"usage": { "prompt_tokens": 12, "completion_tokens": 4, "total_tokens": 16 }
+			}"#))
+			.mount(&server)
+			.await;
+
+		let req = llm::GenRequest {
+			model: llm::LLMModel::new(llm::CUSTOM_PROVIDER, "qwen2.5-coder:7b"),
+			custom_provider: llm::CustomProvider {
+				base_url: format!("{}/v1", server.uri()),
+				api_key_env: "".to_string(),
+			},
+			messages: vec![llm::LLMMessage::user("Hello")],
+			..Default::default()
+		};
+		let (tx, _rx) = tokio::sync::mpsc::unbounded_channel();
+		let res = CustomOpenAIProvider::new(Client::new()).gen(req, &tx).await.unwrap();
+
+		assert_eq!(res.msg.content, "Hello from ollama");
+		assert_eq!(res.total_tokens, 16);
+		assert_eq!(res.promt_cost, MicroUsd::default());
+	}
+
+	#[tokio::test]
+	async fn test_reasoning_model_request() {
+		use wiremock::matchers::body_partial_json;
+		use wiremock::matchers::method;
+		use wiremock::Mock;
+		use wiremock::MockServer;
+		use wiremock::ResponseTemplate;
+
+		let server = MockServer::start().await;
+		Mock::given(method("POST"))
+			.and(body_partial_json(serde_json::json!({
+				"model": "o3-mini",
+				"max_completion_tokens": 2000,
+				"reasoning_effort": "high",
+				"messages": [{ "role": "developer", "content": "Be brief" }],
+			})))
+			.respond_with(ResponseTemplate::new(200).set_body_string(r#"{
+				"id": "chatcmpl-1",
+				"object": "chat.completion",
+				"created": 12412412,
+				"model": "o3-mini",
+				"choices": [{
+					"index": 0,
+					"message": { "role": "assistant", "content": "42" },
+					"finish_reason": "stop"
+				}],
+				"usage": {
+					"prompt_tokens": 20,
+					"completion_tokens": 500,
+					"total_tokens": 520,
+					"prompt_tokens_details": { "cached_tokens": 16 },
+					"completion_tokens_details": { "reasoning_tokens": 480 }
+				}
+			}"#))
+			.mount(&server)
+			.await;
+
+		let req = llm::GenRequest {
+			model: llm::LLMModel::new("openai", O3_MINI),
+			messages: vec![
+				llm::LLMMessage::System("Be brief".to_string()),
+				llm::LLMMessage::user("What is the answer?"),
+			],
+			params: llm::GenParams {
+				temperature: Some(0.2),
+				max_tokens: Some(2000),
+				reasoning_effort: Some(llm::ReasoningEffort::High),
+				..Default::default()
+			},
+			reasoning: true,
+			..Default::default()
+		};
+		let (tx, _rx) = tokio::sync::mpsc::unbounded_channel();
+		let res = send(req, &Client::new(), &server.uri(), None, &tx).await.unwrap();
+
+		assert_eq!(res.msg.content, "42");
+		assert_eq!(res.completion_tokens, 500);
+		assert_eq!(res.reasoning_tokens, 480);
+		assert_eq!(res.cached_tokens, 16);
+	}
+
+	#[test]
+	fn test_sse_parser_handles_split_chunks() {
+		let mut parser = SseParser::default();
+		let event = "data: {\"a\":\"ä\"}\n\n".as_bytes();
+		// Split inside the two byte encoding of ä.
+		let split = event.iter().position(|b| *b == 0xc3).unwrap() + 1;
+
+		assert!(parser.push(&event[..split]).is_empty());
+		assert_eq!(parser.push(&event[split..]), vec!["{\"a\":\"ä\"}".to_string()]);
+		assert_eq!(parser.push(b": keep-alive\n\ndata: [DONE]\n\n"), vec!["[DONE]".to_string()]);
+	}
+
+	#[tokio::test]
+	async fn test_stream_tool_call_fragments() {
+		use wiremock::matchers::body_partial_json;
+		use wiremock::matchers::method;
+		use wiremock::Mock;
+		use wiremock::MockServer;
+		use wiremock::ResponseTemplate;
+
+		let body = [
+			r#"{"choices":[{"index":0,"delta":{"role":"assistant","content":"Let me "}}]}"#,
+			r#"{"choices":[{"index":0,"delta":{"content":"check."}}]}"#,
+			r#"{"choices":[{"index":0,"delta":{"tool_calls":[{"index":0,"id":"call_1","type":"function","function":{"name":"list_folder_content","arguments":""}}]}}]}"#,
+			r#"{"choices":[{"index":0,"delta":{"tool_calls":[{"index":0,"function":{"arguments":"{\"pa"}}]}}]}"#,
+			r#"{"choices":[{"index":0,"delta":{"tool_calls":[{"index":0,"function":{"arguments":"th\":\"src\"}"}}]}}]}"#,
+			r#"{"choices":[],"usage":{"prompt_tokens":50,"completion_tokens":10,"total_tokens":60}}"#,
+			"[DONE]",
+		].iter().map(|data| format!("data: {}\n\n", data)).collect::<String>();
+
+		let server = MockServer::start().await;
+		Mock::given(method("POST"))
+			.and(body_partial_json(serde_json::json!({ "stream": true })))
+			.respond_with(ResponseTemplate::new(200).set_body_raw(body, "text/event-stream"))
+			.mount(&server)
+			.await;
+
+		let req = llm::GenRequest {
+			model: llm::LLMModel::new("openai", GPT_4O_MINI),
+			messages: vec![llm::LLMMessage::user("What is in src?")],
+			stream: true,
+			..Default::default()
+		};
+		let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+		let res = send(req, &Client::new(), &server.uri(), None, &tx).await.unwrap();
+
+		assert_eq!(res.msg.content, "Let me check.");
+		assert!(matches!(&res.msg.tool_calls[0].tool, crate::generated::ToolCallParameters::ListFolderContent(l) if l.path == "src"));
+		assert_eq!(res.total_tokens, 60);
+
+		let mut content_deltas = 0;
+		let mut tool_call_deltas = 0;
+		while let Ok(result) = rx.try_recv() {
+			match result {
+				llm::GenResult::ContentDelta(_) => content_deltas += 1,
+				llm::GenResult::ToolCallDelta(_) => tool_call_deltas += 1,
+				_ => {}
+			}
+		}
+		assert_eq!(content_deltas, 2);
+		assert_eq!(tool_call_deltas, 3);
+	}
+	#[test]
+	fn test_image_messages() {
+		let image = llm::ImageData {
+			media_type: "image/png".to_string(),
+			data: "iVBORw0KGgo=".to_string(),
+		};
+		let req = llm::GenRequest {
+			messages: vec![
+				llm::LLMMessage::User(vec![
+					llm::ContentPart::Text { text: "What is this?".to_string() },
+					llm::ContentPart::Image(image.clone()),
+				]),
+				llm::LLMMessage::ToolResponse(llm::ToolResponse {
+					id: "call_1".to_string(),
+					content: "Image logo.png".to_string(),
+					images: vec![image],
+				}),
+			],
+			..Default::default()
+		};
+
+		let messages = serde_json::to_value(to_oai_messages(&req)).unwrap();
+		assert_eq!(messages[0]["content"][1], serde_json::json!({
+			"type": "image_url",
+			"image_url": { "url": "data:image/png;base64,iVBORw0KGgo=" }
+		}));
+		assert_eq!(messages[1]["content"], "Image logo.png");
+		assert_eq!(messages[2]["role"], "user");
+		assert_eq!(messages[2]["content"][0]["type"], "image_url");
+	}
 }
\ No newline at end of file
@@ -0,0 +1,252 @@
+use std::collections::BTreeMap;
+use std::collections::VecDeque;
+use std::path::Path;
+use std::process::Stdio;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+use chrono::DateTime;
+use chrono::Utc;
+use serde::Serialize;
+use tokio::io::AsyncBufReadExt;
+use tokio::io::AsyncRead;
+use tokio::io::BufReader;
+use tokio::process::Child;
+use tokio::process::Command;
+
+/// Lines of output kept per process, older lines are dropped.
+pub const MAX_LOG_LINES: usize = 500;
+/// Longer lines are cut, progress bars can print a lot without a newline.
+const MAX_LINE_CHARS: usize = 2000;
+/// Lines returned when the model does not ask for a number.
+pub const DEFAULT_TAIL_LINES: usize = 50;
+
+/// Recent output of a process, stdout and stderr interleaved as they arrive.
+#[derive(Debug, Default)]
+struct OutputLog {
+	lines: VecDeque<String>,
+	dropped: usize,
+}
+
+impl OutputLog {
+	fn push(&mut self, mut line: String) {
+		if let Some((inx, _)) = line.char_indices().nth(MAX_LINE_CHARS) {
+			line.truncate(inx);
+			line += " [...]";
+		}
+		self.lines.push_back(line);
+		if self.lines.len() > MAX_LOG_LINES {
+			self.lines.pop_front();
+			self.dropped += 1;
+		}
+	}
+
+	fn tail(&self, count: usize) -> String {
+		let skip = self.lines.len().saturating_sub(count);
+		let mut res = String::new();
+		if skip + self.dropped > 0 {
+			res += &format!("[... {} earlier lines ...]\n", skip + self.dropped);
+		}
+		for line in self.lines.iter().skip(skip) {
+			res += line;
+			res += "\n";
+		}
+		res
+	}
+}
+
+async fn read_lines(reader: impl AsyncRead + Unpin, log: Arc<Mutex<OutputLog>>) {
+	let mut reader = BufReader::new(reader);
+	let mut line = Vec::new();
+	while let Ok(n) = reader.read_until(b'\n', &mut line).await {
+		if n == 0 {
+			break;
+		}
+		let text = String::from_utf8_lossy(&line).trim_end_matches(['\n', '\r']).to_string();
+		log.lock().unwrap().push(text);
+		line.clear();
+	}
+}
+
+/// State of a background process as shown to the model and in the UI.
+#[derive(Debug, Clone, Serialize)]
+pub struct ProcessInfo {
+	pub name: String,
+	pub cmd: String,
+	pub started: DateTime<Utc>,
+	pub running: bool,
+	/// Not set while running or when the process was killed.
+	pub exit_code: Option<i32>,
+}
+
+#[derive(Debug)]
+struct BackgroundProcess {
+	cmd: String,
+	started: DateTime<Utc>,
+	child: Child,
+	exit_code: Option<i32>,
+	exited: bool,
+	log: Arc<Mutex<OutputLog>>,
+}
+
+impl BackgroundProcess {
+	fn update(&mut self) {
+		if self.exited {
+			return;
+		}
+		if let Ok(Some(status)) = self.child.try_wait() {
+			self.exited = true;
+			self.exit_code = status.code();
+		}
+	}
+
+	/// Kills the process and everything it started, dev servers often run
+	/// the actual server in a child process.
+	fn kill(&mut self) {
+		self.update();
+		if self.exited {
+			return;
+		}
+		#[cfg(unix)]
+		if let Some(pid) = self.child.id() {
+			let _ = std::process::Command::new("kill").arg("-KILL").arg(format!("-{}", pid)).status();
+		}
+		let _ = self.child.start_kill();
+		self.exited = true;
+	}
+
+	fn info(&self, name: &str) -> ProcessInfo {
+		ProcessInfo {
+			name: name.to_string(),
+			cmd: self.cmd.clone(),
+			started: self.started,
+			running: !self.exited,
+			exit_code: self.exit_code,
+		}
+	}
+}
+
+impl Drop for BackgroundProcess {
+	fn drop(&mut self) {
+		self.kill();
+	}
+}
+
+/// Named processes the model started in a project, like dev servers and
+/// watchers. They run until stopped or until the project is closed, which
+/// drops the manager and kills them.
+#[derive(Debug, Default)]
+pub struct ProcessManager {
+	processes: Mutex<BTreeMap<String, BackgroundProcess>>,
+}
+
+impl ProcessManager {
+	/// Starts `cmd` with `bash -c` in `folder`. A process which exited is
+	/// replaced by a new one with the same name.
+	pub fn start(&self, name: &str, cmd: &str, folder: &Path) -> anyhow::Result<ProcessInfo> {
+		let mut processes = self.processes.lock().unwrap();
+		if let Some(process) = processes.get_mut(name) {
+			process.update();
+			if !process.exited {
+				anyhow::bail!("process {} is already running, stop it first", name);
+			}
+		}
+
+		let mut command = Command::new("bash");
+		command.arg("-c")
+			.arg(cmd)
+			.current_dir(folder)
+			.stdin(Stdio::null())
+			.stdout(Stdio::piped())
+			.stderr(Stdio::piped())
+			.kill_on_drop(true);
+		// A group of its own so it can be killed with its children.
+		#[cfg(unix)]
+		command.process_group(0);
+		let mut child = command.spawn()?;
+
+		let log = Arc::new(Mutex::new(OutputLog::default()));
+		tokio::spawn(read_lines(child.stdout.take().unwrap(), log.clone()));
+		tokio::spawn(read_lines(child.stderr.take().unwrap(), log.clone()));
+		let process = BackgroundProcess {
+			cmd: cmd.to_string(),
+			started: Utc::now(),
+			child,
+			exit_code: None,
+			exited: false,
+			log,
+		};
+		let info = process.info(name);
+		processes.insert(name.to_string(), process);
+		Ok(info)
+	}
+
+	pub fn list(&self) -> Vec<ProcessInfo> {
+		let mut processes = self.processes.lock().unwrap();
+		processes.iter_mut().map(|(name, process)| {
+			process.update();
+			process.info(name)
+		}).collect()
+	}
+
+	/// State and the last `lines` lines of output of a process.
+	pub fn output(&self, name: &str, lines: usize) -> anyhow::Result<(ProcessInfo, String)> {
+		let mut processes = self.processes.lock().unwrap();
+		let process = processes.get_mut(name).ok_or_else(|| anyhow::anyhow!("no process named {}", name))?;
+		process.update();
+		let output = process.log.lock().unwrap().tail(lines);
+		Ok((process.info(name), output))
+	}
+
+	/// Kills a process. It stays listed so its output can still be read.
+	pub fn stop(&self, name: &str) -> anyhow::Result<ProcessInfo> {
+		let mut processes = self.processes.lock().unwrap();
+		let process = processes.get_mut(name).ok_or_else(|| anyhow::anyhow!("no process named {}", name))?;
+		process.kill();
+		Ok(process.info(name))
+	}
+
+	pub fn stop_all(&self) {
+		for process in self.processes.lock().unwrap().values_mut() {
+			process.kill();
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::time::Duration;
+
+	async fn wait_for(manager: &ProcessManager, name: &str, done: impl Fn(&ProcessInfo, &str) -> bool) -> (ProcessInfo, String) {
+		tokio::time::timeout(Duration::from_secs(5), async {
+			loop {
+				let (info, output) = manager.output(name, DEFAULT_TAIL_LINES).unwrap();
+				if done(&info, &output) {
+					return (info, output);
+				}
+				tokio::time::sleep(Duration::from_millis(20)).await;
+			}
+		}).await.unwrap()
+	}
+
+	#[tokio::test]
+	async fn test_processes() {
+		let folder = std::env::temp_dir();
+		let manager = ProcessManager::default();
+		manager.start("server", "echo ready; echo warn >&2; sleep 30", &folder).unwrap();
+		assert!(manager.start("server", "true", &folder).is_err());
+		// stdout and stderr are read separately so their order is not known.
+		let (info, _) = wait_for(&manager, "server", |_, output| output.contains("ready\n") && output.contains("warn\n")).await;
+		assert!(info.running);
+
+		let info = manager.stop("server").unwrap();
+		assert!(!info.running);
+		assert!(manager.output("server", 1).unwrap().1.starts_with("[... 1 earlier lines ...]"));
+
+		manager.start("build", "seq 3; exit 3", &folder).unwrap();
+		let (info, _) = wait_for(&manager, "build", |info, output| !info.running && output == "1\n2\n3\n").await;
+		assert_eq!(info.exit_code, Some(3));
+		assert_eq!(manager.list().len(), 2);
+	}
+}
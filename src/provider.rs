@@ -0,0 +1,193 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use tokio::sync::mpsc::UnboundedSender;
+use crate::ledger::MicroUsd;
+use crate::llm::GenRequest;
+use crate::llm::GenResult;
+use crate::llm::SuccessfullGenResponse;
+
+fn default_true() -> bool {
+	true
+}
+
+/// Price version of the built in models.
+pub const PRICE_VERSION: &str = "2025-01";
+
+/// Capabilities and pricing of a single model. Prices are in USD per million
+/// tokens since that is how providers publish them.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct ModelInfo {
+	pub name: String,
+	pub context_window: u32,
+	#[serde(default = "default_true")]
+	pub tools: bool,
+	#[serde(default)]
+	pub vision: bool,
+	/// Thinks before answering, like the OpenAI o-series.
+	#[serde(default)]
+	pub reasoning: bool,
+	pub input_price: f32,
+	pub output_price: f32,
+	/// Price of prompt tokens read from the cache. 0 means no discount.
+	#[serde(default)]
+	pub cached_input_price: f32,
+	/// Recorded with every ledger entry. Change it when editing the prices
+	/// so old entries can be told apart from new ones.
+	#[serde(default)]
+	pub price_version: String,
+}
+
+impl ModelInfo {
+	pub fn input_cost(&self, token_count: u32) -> MicroUsd {
+		MicroUsd::for_tokens(token_count, self.input_price)
+	}
+
+	/// Cost of a prompt of which `cached_tokens` were read from the cache.
+	pub fn prompt_cost(&self, prompt_tokens: u32, cached_tokens: u32) -> MicroUsd {
+		let cached_tokens = cached_tokens.min(prompt_tokens);
+		self.input_cost(prompt_tokens - cached_tokens) + self.cached_input_cost(cached_tokens)
+	}
+
+	pub fn cached_input_cost(&self, token_count: u32) -> MicroUsd {
+		if self.cached_input_price == 0.0 {
+			return self.input_cost(token_count);
+		}
+		MicroUsd::for_tokens(token_count, self.cached_input_price)
+	}
+
+	/// Cost of `token_count` output tokens. Reasoning tokens are billed as
+	/// output so they must be included in the count.
+	pub fn output_cost(&self, token_count: u32) -> MicroUsd {
+		MicroUsd::for_tokens(token_count, self.output_price)
+	}
+}
+
+/// Backend which can generate responses for `GenRequest`s.
+///
+/// Providers only report token counts, costs are filled in by `LLMClient`
+/// from the `ModelInfo` of the requested model. Deltas of streamed responses
+/// are sent to `tx` before the final response is returned.
+#[async_trait]
+pub trait LLMProvider: Send + Sync {
+	fn id(&self) -> &str;
+	fn models(&self) -> Vec<ModelInfo>;
+	async fn gen(&self, req: GenRequest, tx: &UnboundedSender<GenResult>) -> anyhow::Result<SuccessfullGenResponse>;
+}
+
+/// Providers keyed by their id, kept in registration order.
+#[derive(Default)]
+pub struct ProviderRegistry {
+	providers: Vec<Arc<dyn LLMProvider>>,
+}
+
+impl ProviderRegistry {
+	/// Registers a provider, replacing an earlier one with the same id.
+	pub fn register(&mut self, provider: Arc<dyn LLMProvider>) {
+		match self.providers.iter().position(|p| p.id() == provider.id()) {
+			Some(inx) => self.providers[inx] = provider,
+			None => self.providers.push(provider),
+		}
+	}
+
+	pub fn get(&self, id: &str) -> Option<Arc<dyn LLMProvider>> {
+		self.providers.iter().find(|p| p.id() == id).cloned()
+	}
+
+	/// Built in models of all providers with the id of their provider.
+	pub fn models(&self) -> Vec<(String, ModelInfo)> {
+		self.providers.iter().flat_map(|p| {
+			p.models().into_iter().map(|m| (p.id().to_string(), m))
+		}).collect()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::llm::AssistantMsg;
+	use crate::llm::LLMClient;
+	use crate::llm::LLMMessage;
+	use crate::llm::LLMModel;
+	use crate::llm::parts_text;
+
+	struct EchoProvider;
+
+	#[async_trait]
+	impl LLMProvider for EchoProvider {
+		fn id(&self) -> &str {
+			"echo"
+		}
+
+		fn models(&self) -> Vec<ModelInfo> {
+			vec![ModelInfo {
+				name: "echo-1".to_string(),
+				context_window: 1000,
+				tools: false,
+				vision: false,
+				reasoning: false,
+				input_price: 1_000_000.0,
+				output_price: 2_000_000.0,
+				cached_input_price: 500_000.0,
+				price_version: PRICE_VERSION.to_string(),
+			}]
+		}
+
+		async fn gen(&self, req: GenRequest, _tx: &UnboundedSender<GenResult>) -> anyhow::Result<SuccessfullGenResponse> {
+			let content = match req.messages.last() {
+				Some(LLMMessage::User(parts)) => parts_text(parts),
+				_ => String::new(),
+			};
+			Ok(SuccessfullGenResponse {
+				prompt_tokens: 3,
+				cached_tokens: 2,
+				completion_tokens: 5,
+				total_tokens: 8,
+				reasoning_tokens: 0,
+				promt_cost: MicroUsd::default(),
+				completion_cost: MicroUsd::default(),
+				msg: AssistantMsg {
+					content,
+					tool_calls: vec![],
+				},
+			})
+		}
+	}
+
+	#[tokio::test]
+	async fn test_registered_provider_is_used_and_priced() {
+		let mut client = LLMClient::new();
+		client.register(Arc::new(EchoProvider));
+
+		let model = LLMModel::new("echo", "echo-1");
+		assert!(client.models().contains(&model));
+		assert!(!client.model_info(&model).unwrap().tools);
+
+		client.gen(GenRequest {
+			model,
+			messages: vec![LLMMessage::user("woof")],
+			..Default::default()
+		});
+
+		match client.next().await.unwrap().1 {
+			GenResult::Response(res) => {
+				assert_eq!(res.msg.content, "woof");
+				// One uncached token and two at the cached price.
+				assert_eq!(res.promt_cost, MicroUsd::from_usd(2.0));
+				assert_eq!(res.completion_cost, MicroUsd::from_usd(10.0));
+			}
+			res => panic!("unexpected result {:?}", res),
+		}
+	}
+
+	#[tokio::test]
+	async fn test_unknown_provider_is_an_error() {
+		let mut client = LLMClient::new();
+		client.gen(GenRequest {
+			model: LLMModel::new("nope", "nope-1"),
+			..Default::default()
+		});
+
+		assert!(matches!(client.next().await.unwrap().1, GenResult::Error(_)));
+	}
+}
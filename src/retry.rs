@@ -0,0 +1,196 @@
+use std::collections::hash_map::RandomState;
+use std::fmt;
+use std::hash::BuildHasher;
+use std::time::Duration;
+
+use reqwest::header::HeaderMap;
+use reqwest::StatusCode;
+
+/// Non success response from a provider. `retry_after` is the wait time the
+/// provider asked for, if it sent any.
+#[derive(Debug)]
+pub struct HttpError {
+	pub status: StatusCode,
+	pub retry_after: Option<Duration>,
+	pub body: String,
+}
+
+impl HttpError {
+	pub fn new(status: StatusCode, headers: &HeaderMap, body: String) -> HttpError {
+		HttpError {
+			status,
+			retry_after: retry_after(headers),
+			body,
+		}
+	}
+}
+
+impl fmt::Display for HttpError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "request failed with code {}", self.status)?;
+		if !self.body.is_empty() {
+			write!(f, ": {}", self.body)?;
+		}
+		Ok(())
+	}
+}
+
+impl std::error::Error for HttpError {}
+
+fn header<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+	headers.get(name)?.to_str().ok()
+}
+
+/// Parses durations like `1s`, `6m0s` or `250ms` used by the
+/// `x-ratelimit-reset-*` headers.
+fn parse_reset_duration(s: &str) -> Option<Duration> {
+	let mut total = 0.0;
+	let mut number = String::new();
+	let mut chars = s.trim().chars().peekable();
+	while let Some(c) = chars.next() {
+		if c.is_ascii_digit() || c == '.' {
+			number.push(c);
+			continue;
+		}
+		let value: f64 = number.parse().ok()?;
+		number.clear();
+		total += match c {
+			'h' => value * 3600.0,
+			'm' if chars.peek() == Some(&'s') => {
+				chars.next();
+				value / 1000.0
+			}
+			'm' => value * 60.0,
+			's' => value,
+			_ => return None,
+		};
+	}
+	if !number.is_empty() {
+		total += number.parse::<f64>().ok()?;
+	}
+	Some(Duration::from_secs_f64(total))
+}
+
+fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+	if let Some(ms) = header(headers, "retry-after-ms").and_then(|v| v.parse::<u64>().ok()) {
+		return Some(Duration::from_millis(ms));
+	}
+	if let Some(value) = header(headers, "retry-after") {
+		if let Ok(secs) = value.parse::<u64>() {
+			return Some(Duration::from_secs(secs));
+		}
+		if let Ok(date) = chrono::DateTime::parse_from_rfc2822(value) {
+			let wait = date.with_timezone(&chrono::Utc) - chrono::Utc::now();
+			return Some(wait.to_std().unwrap_or_default());
+		}
+	}
+	// Only wait for the limits which are actually exhausted.
+	["requests", "tokens"].iter().filter_map(|kind| {
+		if header(headers, &format!("x-ratelimit-remaining-{}", kind))? != "0" {
+			return None;
+		}
+		parse_reset_duration(header(headers, &format!("x-ratelimit-reset-{}", kind))?)
+	}).max()
+}
+
+fn is_retryable(err: &anyhow::Error) -> bool {
+	if let Some(err) = err.downcast_ref::<HttpError>() {
+		// 529 is Anthropic's overloaded status.
+		return err.status == StatusCode::TOO_MANY_REQUESTS || err.status.is_server_error() || err.status.as_u16() == 529;
+	}
+	if let Some(err) = err.downcast_ref::<reqwest::Error>() {
+		return err.is_timeout() || err.is_connect();
+	}
+	false
+}
+
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+	/// Total number of attempts including the first one.
+	pub max_attempts: u32,
+	pub base_delay: Duration,
+	pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+	fn default() -> RetryPolicy {
+		RetryPolicy {
+			max_attempts: 4,
+			base_delay: Duration::from_secs(1),
+			max_delay: Duration::from_secs(60),
+		}
+	}
+}
+
+impl RetryPolicy {
+	/// Returns how long to wait before the next attempt or `None` when the
+	/// error is permanent or attempts are used up. `attempt` starts from 1.
+	pub fn retry_delay(&self, attempt: u32, err: &anyhow::Error) -> Option<Duration> {
+		if attempt >= self.max_attempts || !is_retryable(err) {
+			return None;
+		}
+		if let Some(wait) = err.downcast_ref::<HttpError>().and_then(|e| e.retry_after) {
+			return Some(wait);
+		}
+		let backoff = self.base_delay.saturating_mul(2u32.saturating_pow(attempt - 1)).min(self.max_delay);
+		// Equal jitter: wait at least half of the backoff so clients spread out
+		// without retrying immediately.
+		let jitter = RandomState::new().hash_one(attempt) % (backoff.as_millis() as u64 / 2 + 1);
+		Some(backoff / 2 + Duration::from_millis(jitter))
+	}
+}
+
+#[derive(Debug, Clone)]
+pub struct RetryInfo {
+	/// Attempt which failed, starting from 1.
+	pub attempt: u32,
+	pub max_attempts: u32,
+	pub delay: Duration,
+	pub reason: String,
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use reqwest::header::HeaderValue;
+
+	fn http_error(status: u16, headers: &[(&'static str, &'static str)]) -> anyhow::Error {
+		let mut map = HeaderMap::new();
+		for (name, value) in headers {
+			map.insert(*name, HeaderValue::from_static(value));
+		}
+		HttpError::new(StatusCode::from_u16(status).unwrap(), &map, String::new()).into()
+	}
+
+	#[test]
+	fn test_parse_reset_duration() {
+		assert_eq!(parse_reset_duration("1s"), Some(Duration::from_secs(1)));
+		assert_eq!(parse_reset_duration("6m0s"), Some(Duration::from_secs(360)));
+		assert_eq!(parse_reset_duration("250ms"), Some(Duration::from_millis(250)));
+		assert_eq!(parse_reset_duration("1.5s"), Some(Duration::from_millis(1500)));
+		assert_eq!(parse_reset_duration("soon"), None);
+	}
+
+	#[test]
+	fn test_retry_delay() {
+		let policy = RetryPolicy::default();
+
+		let err = http_error(429, &[("retry-after", "7")]);
+		assert_eq!(policy.retry_delay(1, &err), Some(Duration::from_secs(7)));
+		let err = http_error(429, &[
+			("x-ratelimit-remaining-requests", "10"),
+			("x-ratelimit-reset-requests", "1s"),
+			("x-ratelimit-remaining-tokens", "0"),
+			("x-ratelimit-reset-tokens", "20s"),
+		]);
+		assert_eq!(policy.retry_delay(1, &err), Some(Duration::from_secs(20)));
+
+		let err = http_error(503, &[]);
+		let delay = policy.retry_delay(3, &err).unwrap();
+		assert!(delay >= Duration::from_secs(2) && delay <= Duration::from_secs(4));
+		assert_eq!(policy.retry_delay(4, &err), None);
+
+		assert_eq!(policy.retry_delay(1, &http_error(401, &[])), None);
+		assert_eq!(policy.retry_delay(1, &anyhow::anyhow!("OPENAI_API_KEY not set")), None);
+	}
+}
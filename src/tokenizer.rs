@@ -0,0 +1,134 @@
+use tiktoken_rs::cl100k_base_singleton;
+use tiktoken_rs::o200k_base_singleton;
+use crate::generated::Tool;
+use crate::llm::ContentPart;
+use crate::llm::LLMMessage;
+use crate::llm::LLMModel;
+
+/// Context window assumed for models missing from the catalog.
+pub const DEFAULT_CONTEXT_WINDOW: u32 = 8192;
+/// Part of the context window left for the response.
+pub const RESERVED_OUTPUT_TOKENS: u32 = 4096;
+/// Role and separators the chat format adds to every message.
+const TOKENS_PER_MESSAGE: u32 = 4;
+/// Estimate for an image, what OpenAI charges for a 1024x1024 image in high
+/// detail. The real cost depends on the size and the provider.
+pub const TOKENS_PER_IMAGE: u32 = 765;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Encoding {
+	Cl100k,
+	O200k,
+}
+
+impl Encoding {
+	/// Encoding of `model`. Only OpenAI publishes its tokenizers so counts for
+	/// other models are estimates made with cl100k.
+	pub fn for_model(model: &LLMModel) -> Encoding {
+		let name = model.name.as_str();
+		if ["gpt-4o", "gpt-4.1", "o1", "o3", "o4"].iter().any(|prefix| name.starts_with(prefix)) {
+			Encoding::O200k
+		} else {
+			Encoding::Cl100k
+		}
+	}
+
+	pub fn count(&self, text: &str) -> u32 {
+		let bpe = match self {
+			Encoding::Cl100k => cl100k_base_singleton(),
+			Encoding::O200k => o200k_base_singleton(),
+		};
+		let bpe = bpe.lock();
+		bpe.encode_with_special_tokens(text).len() as u32
+	}
+
+	pub fn count_message(&self, msg: &LLMMessage) -> u32 {
+		let content = match msg {
+			LLMMessage::System(text) => self.count(text),
+			LLMMessage::User(parts) => parts.iter().map(|part| match part {
+				ContentPart::Text { text } => self.count(text),
+				ContentPart::Image(_) => TOKENS_PER_IMAGE,
+			}).sum(),
+			LLMMessage::Assistant(msg) => {
+				self.count(&msg.content) + msg.tool_calls.iter().map(|tool_call| {
+					self.count(tool_call.tool.get_name()) + self.count(&tool_call.tool.get_args())
+				}).sum::<u32>()
+			}
+			LLMMessage::ToolResponse(res) => {
+				self.count(&res.id) + self.count(&res.content) + res.images.len() as u32 * TOKENS_PER_IMAGE
+			}
+		};
+		content + TOKENS_PER_MESSAGE
+	}
+
+	/// Tokens taken by the tool definitions sent with a request.
+	pub fn count_tools(&self, tools: &[Tool]) -> u32 {
+		tools.iter().map(|tool| {
+			self.count(tool.get_name()) + self.count(tool.get_description()) + self.count(&tool.get_parameters().to_string())
+		}).sum()
+	}
+}
+
+/// Drops the oldest messages until the rest fit in `budget` tokens. System
+/// messages are always kept and so is the newest message, even if it alone
+/// is over the budget. Tool responses whose tool call was dropped are removed
+/// too since providers reject them.
+pub fn fit_to_budget(encoding: Encoding, messages: Vec<LLMMessage>, budget: u32) -> Vec<LLMMessage> {
+	let (system, rest): (Vec<_>, Vec<_>) = messages.into_iter()
+		.partition(|msg| matches!(msg, LLMMessage::System(_)));
+	let mut used: u32 = system.iter().map(|msg| encoding.count_message(msg)).sum();
+
+	let mut keep = 0;
+	for msg in rest.iter().rev() {
+		used += encoding.count_message(msg);
+		if used > budget && keep > 0 {
+			break;
+		}
+		keep += 1;
+	}
+	let skip = rest.len() - keep;
+	let kept = rest.into_iter()
+		.skip(skip)
+		.skip_while(|msg| matches!(msg, LLMMessage::ToolResponse(_)));
+	system.into_iter().chain(kept).collect()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::llm::parts_text;
+	use crate::llm::ToolResponse;
+
+	#[test]
+	fn test_count() {
+		assert_eq!(Encoding::Cl100k.count("hello world"), 2);
+		assert_eq!(Encoding::O200k.count("hello world"), 2);
+		assert_eq!(Encoding::for_model(&LLMModel::new("openai", "gpt-4o-mini")), Encoding::O200k);
+		assert_eq!(Encoding::for_model(&LLMModel::new("anthropic", "claude-3-5-haiku-latest")), Encoding::Cl100k);
+	}
+
+	#[test]
+	fn test_fit_to_budget() {
+		let encoding = Encoding::Cl100k;
+		let messages = vec![
+			LLMMessage::System("be brief".to_string()),
+			LLMMessage::user(&"one ".repeat(50)),
+			LLMMessage::ToolResponse(ToolResponse {
+				id: "call_1".to_string(),
+				content: "two".to_string(),
+				images: Vec::new(),
+			}),
+			LLMMessage::user("three"),
+		];
+		let all: u32 = messages.iter().map(|msg| encoding.count_message(msg)).sum();
+
+		assert_eq!(fit_to_budget(encoding, messages.clone(), all).len(), 4);
+		// A tool response left first would be orphaned so it is dropped too.
+		let fitted = fit_to_budget(encoding, messages.clone(), all - 1);
+		assert_eq!(fitted.len(), 2);
+		assert!(matches!(&fitted[0], LLMMessage::System(_)));
+		assert!(matches!(&fitted[1], LLMMessage::User(parts) if parts_text(parts) == "three"));
+		// The newest message is sent even if it does not fit.
+		assert_eq!(fit_to_budget(encoding, messages, 0).len(), 2);
+	}
+}
@@ -1,6 +1,9 @@
 use std::io::Seek;
 use std::io::Write;
+use std::collections::HashMap;
 use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
 
 use anyhow::Ok;
 use tokio::fs;
@@ -9,14 +12,62 @@ use tokio::fs::OpenOptions;
 use tokio::io::AsyncReadExt;
 use tokio::io::AsyncSeekExt;
 use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc::UnboundedReceiver;
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::task::JoinHandle;
 
+use crate::bash;
+use crate::bash::Shell;
 use crate::generated::ToolCallParameters;
+use crate::llm::ImageData;
+use crate::llm::ToolCall;
+use crate::llm::ToolResponse;
+use crate::process::ProcessManager;
+use crate::process::DEFAULT_TAIL_LINES;
 use crate::Project;
 
-pub async fn execute(project: &Project, tool: &ToolCallParameters) -> anyhow::Result<String> {
+/// Parts of a project tools need. Owned so tools can run in a separate task.
+#[derive(Debug, Clone)]
+pub struct ToolContext {
+	pub folder_path: String,
+	pub forbidden_files: Vec<String>,
+	pub bash_timeout: Duration,
+	pub shell: Arc<Shell>,
+	pub processes: Arc<ProcessManager>,
+}
+
+impl From<&Project> for ToolContext {
+	fn from(project: &Project) -> ToolContext {
+		ToolContext {
+			folder_path: project.folder_path.clone(),
+			forbidden_files: project.forbidden_files.clone(),
+			bash_timeout: Duration::from_secs(project.bash_timeout_secs.unwrap_or(bash::DEFAULT_TIMEOUT_SECS)),
+			shell: project.shell.clone(),
+			processes: project.processes.clone(),
+		}
+	}
+}
+
+/// Result of a tool call. Images are sent to the model along with the text.
+#[derive(Debug, Default)]
+pub struct ToolOutput {
+	pub content: String,
+	pub images: Vec<ImageData>,
+}
+
+impl From<String> for ToolOutput {
+	fn from(content: String) -> ToolOutput {
+		ToolOutput {
+			content,
+			images: Vec::new(),
+		}
+	}
+}
+
+pub async fn execute(ctx: &ToolContext, tool: &ToolCallParameters) -> anyhow::Result<ToolOutput> {
 	let res = match tool {
 		ToolCallParameters::WriteFile(w) => {
-			let path = Path::new(&project.folder_path).join(&w.path);
+			let path = Path::new(&ctx.folder_path).join(&w.path);
 			if let Some(parent_path) = path.parent() {
 				if !parent_path.exists() {
 					log::info!("parent path does not exist, creating it: {:?}", parent_path);
@@ -24,9 +75,9 @@ pub async fn execute(project: &Project, tool: &ToolCallParameters) -> anyhow::Re
 				}
 			}
 			let file_name = path.file_name().unwrap().to_str().unwrap();
-			if project.forbidden_files.contains(&file_name.to_string()) {
+			if ctx.forbidden_files.contains(&file_name.to_string()) {
 				println!("File {} is forbidden", file_name);
-				return Ok("You are not allowed to write this file".to_string());
+				return Ok("You are not allowed to write this file".to_string().into());
 			}
 
 			let lines_to_write: Vec<&str> = w.content.lines().collect();
@@ -54,7 +105,7 @@ pub async fn execute(project: &Project, tool: &ToolCallParameters) -> anyhow::Re
 			"File written".to_string()
 		},
 		ToolCallParameters::ReadFile(r) => {
-			let path = Path::new(&project.folder_path).join(&r.path);
+			let path = Path::new(&ctx.folder_path).join(&r.path);
 			let mut file = File::open(&path).await?;
 			let mut content = String::new();
 			file.read_to_string(&mut content).await?;
@@ -67,18 +118,18 @@ pub async fn execute(project: &Project, tool: &ToolCallParameters) -> anyhow::Re
 			selected_lines.join("\n")
 		},
 		ToolCallParameters::RemoveFile(r) => {
-			let path = Path::new(&project.folder_path).join(&r.path);
+			let path = Path::new(&ctx.folder_path).join(&r.path);
 			if !path.exists() {
-				return Ok("File does not exist".to_string());
+				return Ok("File does not exist".to_string().into());
 			}
 			tokio::fs::remove_file(&path).await?;
 			"File removed".to_string()
 		}
 		ToolCallParameters::ListFolderContent(args) => {
-			let path = Path::new(&project.folder_path).join(&args.path);
+			let path = Path::new(&ctx.folder_path).join(&args.path);
 
 			if !path.exists() {
-				return Ok("Path does not exist".to_string());
+				return Ok("Path does not exist".to_string().into());
 			}
 
 			let mut paths = fs::read_dir(&path).await?;
@@ -98,8 +149,118 @@ pub async fn execute(project: &Project, tool: &ToolCallParameters) -> anyhow::Re
 		ToolCallParameters::FindInFile(args) => {
 			todo!()
 		},
+		ToolCallParameters::ViewImage(args) => {
+			let path = Path::new(&ctx.folder_path).join(&args.path);
+			let image = ImageData::load(&path).await?;
+			return Ok(ToolOutput {
+				content: format!("Image {}", args.path),
+				images: vec![image],
+			});
+		},
+		ToolCallParameters::ExecuteBashCmd(args) => {
+			let output = ctx.shell.run(&args.cmd, Path::new(&ctx.folder_path), ctx.bash_timeout).await?;
+			serde_json::to_string_pretty(&output)?
+		},
+		ToolCallParameters::StartProcess(args) => {
+			let info = ctx.processes.start(&args.name, &args.cmd, Path::new(&ctx.folder_path))?;
+			serde_json::to_string_pretty(&info)?
+		},
+		ToolCallParameters::ReadProcessOutput(args) => {
+			let lines = args.lines.map_or(DEFAULT_TAIL_LINES, |lines| lines as usize);
+			let (info, output) = ctx.processes.output(&args.name, lines)?;
+			format!("{}\n\n{}", serde_json::to_string_pretty(&info)?, output)
+		},
+		ToolCallParameters::ListProcesses(_) => {
+			serde_json::to_string_pretty(&ctx.processes.list())?
+		},
+		ToolCallParameters::StopProcess(args) => {
+			let info = ctx.processes.stop(&args.name)?;
+			serde_json::to_string_pretty(&info)?
+		},
 		_ => todo!(),
 	};
 
-	Ok(res)
-}
\ No newline at end of file
+	Ok(res.into())
+}
+
+struct ToolBatch {
+	seq: u64,
+	handle: JoinHandle<()>,
+	pending: Vec<String>,
+}
+
+/// Runs tool calls in background tasks so they can be cancelled while the
+/// UI keeps handling events. Each project has at most one batch running and
+/// the responses of a batch are delivered together.
+pub struct ToolRunner {
+	batches: HashMap<usize, ToolBatch>,
+	seq: u64,
+	tx: UnboundedSender<(usize, u64, Vec<ToolResponse>)>,
+	rx: UnboundedReceiver<(usize, u64, Vec<ToolResponse>)>,
+}
+
+impl ToolRunner {
+	pub fn new() -> ToolRunner {
+		let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+		ToolRunner {
+			batches: HashMap::new(),
+			seq: 0,
+			tx,
+			rx,
+		}
+	}
+
+	pub fn run(&mut self, project_id: usize, ctx: ToolContext, tool_calls: Vec<ToolCall>) {
+		self.cancel(project_id);
+		self.seq += 1;
+		let seq = self.seq;
+		let pending = tool_calls.iter().map(|t| t.id.clone()).collect();
+		let tx = self.tx.clone();
+		let handle = tokio::spawn(async move {
+			let mut responses = Vec::new();
+			for tool_call in tool_calls {
+				let output = execute(&ctx, &tool_call.tool).await.unwrap_or_else(|e| {
+					log::info!("tool call error: {:?}", e);
+					e.to_string().into()
+				});
+				log::info!("tool call result: {:?}", output.content);
+				responses.push(ToolResponse {
+					id: tool_call.id,
+					content: output.content,
+					images: output.images,
+				});
+			}
+			tx.send((project_id, seq, responses)).unwrap();
+		});
+		self.batches.insert(project_id, ToolBatch { seq, handle, pending });
+	}
+
+	pub fn is_running(&self, project_id: usize) -> bool {
+		self.batches.contains_key(&project_id)
+	}
+
+	/// Aborts the running batch of the project. Returns the ids of the tool
+	/// calls which did not get a response.
+	pub fn cancel(&mut self, project_id: usize) -> Vec<String> {
+		match self.batches.remove(&project_id) {
+			Some(batch) => {
+				// Dropping the task drops any child process it was waiting for.
+				batch.handle.abort();
+				batch.pending
+			}
+			None => Vec::new(),
+		}
+	}
+
+	/// Responses of the next finished batch, with the id of its project.
+	pub async fn next(&mut self) -> Option<(usize, Vec<ToolResponse>)> {
+		loop {
+			let (project_id, seq, responses) = self.rx.recv().await?;
+			// Skip batches which were cancelled after they finished.
+			if self.batches.get(&project_id).is_some_and(|batch| batch.seq == seq) {
+				self.batches.remove(&project_id);
+				return Some((project_id, responses));
+			}
+		}
+	}
+}
@@ -1,22 +1,91 @@
 use serde::Deserialize;
 use serde_json::Value;
+use std::sync::Arc;
+use crate::bash::Shell;
+use crate::budget::Budget;
+use crate::budget::BudgetExceeded;
+use crate::budget::Spending;
 use crate::generated::Tool;
 use crate::history::History;
+use crate::ledger::DisplayCurrency;
+use crate::ledger::Ledger;
+use crate::ledger::MicroUsd;
+use crate::process::ProcessManager;
+use crate::CustomProvider;
+use crate::GenParams;
+use crate::ImageData;
 use crate::LLMModel;
+use crate::PartialAssistantMsg;
+use crate::retry::RetryInfo;
 
 fn default_folder_path() -> String {
 	"./workdir".to_string()
 }
+/// Runtime state of the agent loop of a project. Not saved.
+#[derive(Debug, Default)]
+pub struct Conversation {
+	pub turns: u32,
+	/// A request or tool call is in progress and can be stopped.
+	pub running: bool,
+	/// Response currently being streamed from the model.
+	pub streaming_msg: Option<PartialAssistantMsg>,
+	/// Set while waiting to retry a failed request.
+	pub retrying: Option<RetryInfo>,
+	pub session_cost: MicroUsd,
+	/// Cost since the last message sent by the user.
+	pub task_cost: MicroUsd,
+	/// Set when a request was held back by the budget.
+	pub budget_exceeded: Option<BudgetExceeded>,
+	/// Send the next request even if it goes over the budget.
+	pub budget_override: bool,
+	/// Tokens in the prompt of the last request.
+	pub prompt_tokens: u32,
+	/// Prompt size set by compacting the context after an overflow. Our
+	/// token counts are estimates for most models so they can come out low.
+	pub context_limit: Option<u32>,
+	/// Position of the model requests go to in the project's model chain,
+	/// 0 for `Project::model`. Reset when the user sends a message.
+	pub fallback: usize,
+}
 
 #[derive(Debug, Default, serde::Serialize, serde::Deserialize)]
 pub struct Project {
+	/// Assigned when the project is loaded, used to route results to it.
+	#[serde(skip)]
+	pub id: usize,
+	#[serde(skip)]
+	pub conversation: Conversation,
+	/// Shell `execute_bash_cmd` runs in, shared with the running tool calls.
+	#[serde(skip)]
+	pub shell: Arc<Shell>,
+	/// Background processes started by the model, killed when dropped.
+	#[serde(skip)]
+	pub processes: Arc<ProcessManager>,
 	pub name: String,
 	#[serde(default)]
 	pub model: LLMModel,
+	/// Tried in order when `model` fails in a way another model can handle.
+	#[serde(default)]
+	pub fallback_models: Vec<LLMModel>,
+	#[serde(default)]
+	pub custom_provider: CustomProvider,
+	#[serde(default)]
+	pub gen_params: GenParams,
 	pub output_token_count: u32,
 	pub input_token_count: u32,
+	/// Part of `input_token_count` read from the prompt cache.
+	#[serde(default)]
+	pub cached_token_count: u32,
+	/// Part of `output_token_count` used for reasoning.
+	#[serde(default)]
+	pub reasoning_token_count: u32,
+	/// Cost totals saved before the ledger was kept, see `migrate_costs`.
+	#[serde(default, skip_serializing)]
 	pub input_token_cost: f32,
+	#[serde(default, skip_serializing)]
 	pub output_token_cost: f32,
+	#[serde(default)]
+	pub ledger: Ledger,
 	pub todo_items: Vec<TodoItem>,
 	pub history: History,
 	pub instructions: String,
@@ -26,24 +95,90 @@ pub struct Project {
 	pub folder_path: String,
 	#[serde(default)]
 	pub forbidden_files: Vec<String>,
+	/// Commands run by the model are killed after this long,
+	/// `bash::DEFAULT_TIMEOUT_SECS` if not set.
+	#[serde(default)]
+	pub bash_timeout_secs: Option<u64>,
+	#[serde(default)]
+	pub budget: Budget,
 	pub modified: bool,
 }
 
+impl Project {
+	/// Model of the conversation's current position in the fallback chain.
+	pub fn active_model(&self) -> &LLMModel {
+		match self.conversation.fallback {
+			0 => &self.model,
+			inx => self.fallback_models.get(inx - 1).unwrap_or(&self.model),
+		}
+	}
+
+	/// Moves the conversation to the next model of the fallback chain, if any.
+	pub fn fall_back(&mut self) -> Option<&LLMModel> {
+		let next = self.fallback_models.get(self.conversation.fallback)?;
+		self.conversation.fallback += 1;
+		Some(next)
+	}
+
+	pub fn spending(&self) -> Spending {
+		Spending {
+			project: self.ledger.total_cost().usd() as f32,
+			session: self.conversation.session_cost.usd() as f32,
+			task: self.conversation.task_cost.usd() as f32,
+		}
+	}
+
+	/// Moves the cost totals of a project saved before the ledger into it.
+	pub fn migrate_costs(&mut self) {
+		let legacy = self.input_token_cost as f64 + self.output_token_cost as f64;
+		self.ledger.carried_over += MicroUsd::from_usd(legacy);
+		self.input_token_cost = 0.0;
+		self.output_token_cost = 0.0;
+	}
+}
+
 #[derive(Debug, serde::Serialize, serde::Deserialize)]
 pub struct TodoItem {
 	pub text: String,
 	pub done: bool,
 }
 
+/// Image attached to the message being written.
+#[derive(Debug, Clone)]
+pub struct Attachment {
+	pub name: String,
+	pub image: ImageData,
+}
+
 #[derive(Debug, Default)]
 pub struct State {
 	pub projects: Vec<Project>,
 	pub active_project: Option<usize>,
 	pub current_msg: String,
+	pub attachments: Vec<Attachment>,
+	/// Token count and input cost of `current_msg` with the active model.
+	pub draft_token_count: u32,
+	pub draft_cost: MicroUsd,
+	pub currency: DisplayCurrency,
 	pub new_forbidden_file_name: String,
 	pub max_conversation_turns: u32,
-	pub conversation_turns: u32,
 	pub max_context_size: u32,
+	/// Models listed in the model catalog.
+	pub models: Vec<LLMModel>,
+	pub max_attempts: u32,
+	pub next_project_id: usize,
+}
+
+impl State {
+	pub fn add_project(&mut self, mut project: Project) {
+		project.id = self.next_project_id;
+		self.next_project_id += 1;
+		self.projects.push(project);
+	}
+
+	pub fn project_mut(&mut self, id: usize) -> Option<&mut Project> {
+		self.projects.iter_mut().find(|p| p.id == id)
+	}
 }
 
 #[derive(Debug, Deserialize)]
@@ -1,11 +1,22 @@
 use wgui::Item;
 use wgui::*;
+use crate::bash::DEFAULT_TIMEOUT_SECS;
+use crate::budget::BudgetExceeded;
+use crate::budget::BudgetScope;
+use crate::error::LLMError;
 use crate::generated::ToolCallParameters;
 use crate::generated::TOOLS;
+use crate::history::Fallback;
+use crate::history::HistoryEntry;
 use crate::types::Project;
 use crate::types::State;
 use crate::types::TodoItem;
+use crate::ContentPart;
+use crate::llm::parts_text;
 use crate::LLMMessage;
+use crate::PartialAssistantMsg;
+use crate::ReasoningEffort;
+use crate::CUSTOM_PROVIDER;
 use crate::ToolCall;
 
 pub const SELECT_PROJECT_LINK: u32 = 1;
@@ -24,6 +35,36 @@ pub const DELETE_FORBIDDEN_FILE_BUTTON: u32 = 13;
 pub const EXPAND_TOOL_CALL: u32 = 14;
 pub const MAX_CONVERSATION_TURNS: u32 = 15;
 pub const MAX_CONTEXT_SIZE: u32 = 16;
+pub const CUSTOM_BASE_URL_INPUT: u32 = 17;
+pub const CUSTOM_API_KEY_ENV_INPUT: u32 = 18;
+pub const CUSTOM_MODEL_INPUT: u32 = 19;
+pub const MAX_ATTEMPTS: u32 = 20;
+pub const STOP_BUTTON: u32 = 21;
+pub const PROJECT_LIMIT_INPUT: u32 = 22;
+pub const SESSION_LIMIT_INPUT: u32 = 23;
+pub const TASK_LIMIT_INPUT: u32 = 24;
+pub const BUDGET_WARNING_INPUT: u32 = 25;
+pub const RAISE_BUDGET_BUTTON: u32 = 26;
+pub const CONTINUE_OVER_BUDGET_BUTTON: u32 = 27;
+pub const TEMPERATURE_INPUT: u32 = 28;
+pub const TOP_P_INPUT: u32 = 29;
+pub const MAX_TOKENS_INPUT: u32 = 30;
+pub const SEED_INPUT: u32 = 31;
+pub const STOP_SEQUENCES_INPUT: u32 = 32;
+pub const PARALLEL_TOOL_CALLS_SELECT: u32 = 33;
+pub const REASONING_EFFORT_SELECT: u32 = 34;
+pub const ATTACH_IMAGE_BUTTON: u32 = 35;
+pub const REMOVE_ATTACHMENT_BUTTON: u32 = 36;
+pub const RETRY_BUTTON: u32 = 37;
+pub const COMPACT_CONTEXT_BUTTON: u32 = 38;
+pub const CURRENCY_INPUT: u32 = 39;
+pub const EXCHANGE_RATE_INPUT: u32 = 40;
+pub const FALLBACK_MODEL_SELECT: u32 = 41;
+pub const REMOVE_FALLBACK_MODEL_BUTTON: u32 = 42;
+pub const BASH_TIMEOUT_INPUT: u32 = 43;
+pub const RESTART_SHELL_BUTTON: u32 = 44;
+pub const STOP_PROCESS_BUTTON: u32 = 45;
+pub const CLOSE_PROJECT_BUTTON: u32 = 46;
 
 fn todo_item_view(todo_item: &TodoItem) -> Item {
 	hstack([
@@ -35,17 +76,147 @@ fn todo_item_view(todo_item: &TodoItem) -> Item {
 	.padding(5)
 }
 
-fn format_cost(cost: f32) -> String {
-	format!("{}€", cost as f32 / 100.0)
+fn format_usd(amount: f32) -> String {
+	format!("${:.2}", amount)
 }
 
-fn tokens_view(project: &Project) -> Item {
+fn budget_view(project: &Project) -> Item {
+	let spending = project.spending();
+	vstack([
+		text("Budget (USD)"),
+		vstack(BudgetScope::ALL.into_iter().map(|scope| {
+			let id = match scope {
+				BudgetScope::Project => PROJECT_LIMIT_INPUT,
+				BudgetScope::Session => SESSION_LIMIT_INPUT,
+				BudgetScope::Task => TASK_LIMIT_INPUT,
+			};
+			let limit = project.budget.limit(scope).map(|limit| limit.to_string()).unwrap_or_default();
+			let border = if project.budget.is_near_limit(&spending, scope) {
+				"1px solid orange"
+			} else {
+				"1px solid transparent"
+			};
+			hstack([
+				text(scope.name()).grow(1),
+				text(&format_usd(spending.get(scope))),
+				text("of"),
+				text_input().placeholder("no limit").svalue(&limit).id(id),
+			])
+			.spacing(5)
+			.border(border)
+		})).spacing(5),
+		text("Warn at % of limit"),
+		text_input().svalue(&(project.budget.warning_threshold * 100.0).to_string()).id(BUDGET_WARNING_INPUT),
+	])
+	.border("1px solid black")
+	.padding(5)
+	.spacing(5)
+}
+
+fn optional_value<T: ToString>(value: &Option<T>) -> String {
+	value.as_ref().map(|v| v.to_string()).unwrap_or_default()
+}
+
+fn gen_params_view(project: &Project) -> Item {
+	let params = &project.gen_params;
+	vstack([
+		text("Generation"),
+		text("Temperature"),
+		text_input().placeholder("default").svalue(&optional_value(&params.temperature)).id(TEMPERATURE_INPUT),
+		text("Top p"),
+		text_input().placeholder("default").svalue(&optional_value(&params.top_p)).id(TOP_P_INPUT),
+		text("Max output tokens"),
+		text_input().placeholder("default").svalue(&optional_value(&params.max_tokens)).id(MAX_TOKENS_INPUT),
+		text("Seed"),
+		text_input().placeholder("random").svalue(&optional_value(&params.seed)).id(SEED_INPUT),
+		text("Stop sequences, one per line"),
+		textarea().svalue(&params.stop.join("\n")).id(STOP_SEQUENCES_INPUT),
+		text("Parallel tool calls"),
+		select([
+			option("", "default"),
+			option("true", "on"),
+			option("false", "off"),
+		])
+		.svalue(&optional_value(&params.parallel_tool_calls))
+		.id(PARALLEL_TOOL_CALLS_SELECT),
+		text("Reasoning effort"),
+		select([
+			option("", "default"),
+			option("low", "low"),
+			option("medium", "medium"),
+			option("high", "high"),
+		])
+		.svalue(match params.reasoning_effort {
+			Some(ReasoningEffort::Low) => "low",
+			Some(ReasoningEffort::Medium) => "medium",
+			Some(ReasoningEffort::High) => "high",
+			None => "",
+		})
+		.id(REASONING_EFFORT_SELECT),
+	])
+	.border("1px solid black")
+	.padding(5)
+	.spacing(5)
+}
+
+fn budget_exceeded_view(exceeded: &BudgetExceeded) -> Item {
+	hstack([
+		text(&format!(
+			"Held back: the next request (~{}) would exceed the {} limit of {} ({} spent)",
+			format_usd(exceeded.estimate), exceeded.scope.name(), format_usd(exceeded.limit), format_usd(exceeded.spent)
+		)).grow(1),
+		button(&format!("Raise limit to {}", format_usd(exceeded.raised_limit()))).id(RAISE_BUDGET_BUTTON),
+		button("Continue once").id(CONTINUE_OVER_BUDGET_BUTTON),
+	])
+	.spacing(10)
+	.border("1px solid red")
+	.padding(5)
+}
+
+/// Actions are only offered on the latest entry, older errors are history.
+fn error_view(error: &LLMError, latest: bool) -> Item {
+	vstack([
+		text(error.title()),
+		text(error.message()),
+		hstack([
+			text(&error.suggestion()).grow(1),
+			match error {
+				LLMError::ContextOverflow { .. } if latest => button("Compact context and retry").id(COMPACT_CONTEXT_BUTTON),
+				_ if latest && error.can_retry() => button("Retry").id(RETRY_BUTTON),
+				_ => vstack([]),
+			},
+		])
+		.spacing(10),
+	])
+	.spacing(10)
+	.grow(1)
+	.border("1px solid red")
+	.padding(5)
+}
+
+fn fallback_view(fallback: &Fallback) -> Item {
+	vstack([
+		text(&format!("Switched from {} to {}", fallback.from.key(), fallback.to.key())),
+		text(&fallback.error.to_string()),
+	])
+	.spacing(10)
+	.grow(1)
+	.border("1px solid orange")
+	.padding(5)
+}
+
+fn tokens_view(state: &State, project: &Project) -> Item {
+	let totals = project.ledger.total();
 	hstack([
 		vstack([
 			text("Input"),
 			text(&format!("{}", project.input_token_count)),
+			text("Cached"),
+			text(&format!("{}", project.cached_token_count)),
 			text("Output"),
 			text(&format!("{}", project.output_token_count)),
+			text("Reasoning"),
+			text(&format!("{}", project.reasoning_token_count)),
 			text("Total"),
 			text(&format!(
 				"{}",
@@ -55,13 +226,15 @@ fn tokens_view(project: &Project) -> Item {
 		.grow(1),
 		vstack([
 			text("Cost"),
-			text(&format_cost(project.input_token_cost)),
+			text(&state.currency.format(totals.prompt_cost)),
+			text("Saved"),
+			text(&state.currency.format(totals.cache_savings)),
 			text("Cost"),
-			text(&format_cost(project.output_token_cost)),
+			text(&state.currency.format(totals.completion_cost)),
+			text(""),
+			text("(in output)"),
 			text("Cost"),
-			text(&format_cost(
-				project.input_token_cost + project.output_token_cost,
-			)),
+			text(&state.currency.format(project.ledger.total_cost())),
 		]),
 	])
 	.border("1px solid black")
@@ -69,6 +242,36 @@ fn tokens_view(project: &Project) -> Item {
 	.spacing(15)
 }
 
+/// Days shown in the spending view.
+const SPENDING_DAYS: usize = 7;
+
+fn spending_view(state: &State, project: &Project) -> Item {
+	let days = project.ledger.by_day();
+	vstack([
+		text("Spending by model"),
+		vstack(project.ledger.by_model().into_iter().map(|(model, totals)| {
+			hstack([
+				text(&model.name).grow(1),
+				text(&format!("{} requests", totals.requests)),
+				text(&state.currency.format(totals.cost())),
+			])
+			.spacing(10)
+		})).spacing(5),
+		text("Spending by day (UTC)"),
+		vstack(days.iter().rev().take(SPENDING_DAYS).map(|(day, totals)| {
+			hstack([
+				text(&day.to_string()).grow(1),
+				text(&format!("{} requests", totals.requests)),
+				text(&state.currency.format(totals.cost())),
+			])
+			.spacing(10)
+		})).spacing(5),
+	])
+	.border("1px solid black")
+	.padding(5)
+	.spacing(5)
+}
+
 fn todo_list_view(todo_items: &Vec<TodoItem>) -> Item {
 	vstack(todo_items.iter().map(todo_item_view)).spacing(10)
 }
@@ -110,10 +313,102 @@ fn forbidden_files(project: &Project) -> Item {
 	.padding(5)
 }
 
-fn send_message_view(msg: &str) -> Item {
+/// Models tried in order when the project's model fails.
+fn fallback_models_view(project: &Project, state: &State) -> Item {
+	vstack([
+		text("Fallback models"),
+		vstack(project.fallback_models.iter().enumerate().map(|(inx, model)| {
+			hstack([
+				text(&format!("{}. {}", inx + 1, model.key())).grow(1),
+				button("remove").id(REMOVE_FALLBACK_MODEL_BUTTON).inx(inx as u32),
+			])
+		})),
+		select([option("", "Add fallback model")].into_iter().chain(
+			state.models.iter().map(|model| option(&model.key(), &model.key()))
+		))
+		.svalue("")
+		.id(FALLBACK_MODEL_SELECT),
+	])
+	.border("1px solid black")
+	.spacing(10)
+	.padding(5)
+}
+
+fn processes_view(project: &Project) -> Item {
+	vstack([
+		text("Background processes"),
+		vstack(project.processes.list().into_iter().enumerate().map(|(inx, info)| {
+			let status = match (info.running, info.exit_code) {
+				(true, _) => "running".to_string(),
+				(false, Some(code)) => format!("exited with {}", code),
+				(false, None) => "stopped".to_string(),
+			};
+			hstack([
+				vstack([
+					text(&format!("{} ({})", info.name, status)),
+					text(&info.cmd),
+				]).grow(1),
+				if info.running {
+					button("Stop").id(STOP_PROCESS_BUTTON).inx(inx as u32)
+				} else {
+					vstack([])
+				},
+			])
+			.spacing(5)
+		})),
+	])
+	.border("1px solid black")
+	.spacing(10)
+	.padding(5)
+}
+
+fn custom_provider_view(project: &Project) -> Item {
+	vstack([
+		text("Custom provider"),
+		text("Base url"),
+		text_input().placeholder("http://localhost:11434/v1").svalue(&project.custom_provider.base_url).id(CUSTOM_BASE_URL_INPUT),
+		text("Api key env"),
+		text_input().placeholder("empty for no auth").svalue(&project.custom_provider.api_key_env).id(CUSTOM_API_KEY_ENV_INPUT),
+		text("Model"),
+		text_input().placeholder("model name").svalue(&project.model.name).id(CUSTOM_MODEL_INPUT),
+	])
+	.border("1px solid black")
+	.spacing(5)
+	.padding(5)
+}
+
+fn attachments_view(state: &State) -> Item {
+	hstack(state.attachments.iter().enumerate().map(|(inx, attachment)| {
+		hstack([
+			text(&attachment.name),
+			button("x").id(REMOVE_ATTACHMENT_BUTTON).inx(inx as u32),
+		])
+		.spacing(5)
+		.padding(5)
+		.border("1px solid black")
+	}))
+	.spacing(5)
+}
+
+fn send_message_view(state: &State, running: bool) -> Item {
+	let msg = &state.current_msg;
 	hstack([
-		textarea().placeholder("Message").grow(1).id(MESSAGE_INPUT).svalue(msg).min_height(35).svalue(msg),
-		button("Send").id(SEND_MESSAGE_BUTTON).max_height(50)
+		vstack([
+			textarea().placeholder("Message").id(MESSAGE_INPUT).svalue(msg).min_height(35).svalue(msg),
+			attachments_view(state),
+		])
+		.spacing(5)
+		.grow(1),
+		button("Attach image").id(ATTACH_IMAGE_BUTTON).max_height(50),
+		vstack([
+			text(&format!("{} tokens", state.draft_token_count)),
+			text(&state.currency.format(state.draft_cost)),
+		]),
+		if running {
+			button("Stop").id(STOP_BUTTON).max_height(50)
+		} else {
+			button("Send").id(SEND_MESSAGE_BUTTON).max_height(50)
+		}
 	])
 	.spacing(5)
 }
@@ -183,6 +478,19 @@ fn tool_call_view(tool_call: &ToolCall) -> Item {
 
 
 
+fn streaming_msg_view(msg: &PartialAssistantMsg) -> Item {
+	vstack([
+		text("Assistant (streaming)"),
+		multile_text(&msg.content),
+		vstack(msg.tool_calls.iter().map(|tool_call| {
+			text(&format!("{}({})", tool_call.name, tool_call.arguments))
+		})).spacing(5),
+	])
+	.spacing(10)
+	.padding(5)
+	.border("1px solid orange")
+}
+
 fn project_view(project: &Project, state: &State) -> Item {
 	hstack([
 		vstack([
@@ -193,27 +501,54 @@ fn project_view(project: &Project, state: &State) -> Item {
 					.id(INSTRUCTIONS_TEXT_INPUT)
 					.height(35)
 					.grow(1),
-				select([
-					option("gpt-4o-mini", "gpt-4o-mini"),
-					option("gpt-4o", "gpt-4o"),
-				])
-				.svalue(project.model.to_str())
+				select(state.models.iter().map(|model| option(&model.key(), &model.key())).chain([
+					if project.model.provider == CUSTOM_PROVIDER {
+						option(&project.model.key(), CUSTOM_PROVIDER)
+					} else {
+						option(&format!("{}/", CUSTOM_PROVIDER), CUSTOM_PROVIDER)
+					}
+				]))
+				.svalue(&project.model.key())
 				.id(MODEL_SELECT)
 				.max_height(35),
 			]).spacing(5),
-			send_message_view(&state.current_msg),
-			vstack(project.history.items.iter().rev().map(|item| {
+			send_message_view(state, project.conversation.running),
+			match &project.conversation.budget_exceeded {
+				Some(exceeded) => budget_exceeded_view(exceeded),
+				None => vstack([]),
+			},
+			match &project.conversation.retrying {
+				Some(info) => text(&format!(
+					"Attempt {}/{} failed ({}), retrying in {:.1}s",
+					info.attempt, info.max_attempts, info.reason, info.delay.as_secs_f32()
+				)).border("1px solid orange").padding(5),
+				None => vstack([]),
+			},
+			match &project.conversation.streaming_msg {
+				Some(msg) => streaming_msg_view(msg),
+				None => vstack([]),
+			},
+			vstack(project.history.items.iter().rev().enumerate().map(|(inx, item)| {
 				hstack([
 					match &item.content {
-						LLMMessage::User(content) => {
-							vstack([text("User"), text(&content)])
-								.spacing(10)				
+						HistoryEntry::Error(error) => error_view(error, inx == 0),
+						HistoryEntry::Fallback(fallback) => fallback_view(fallback),
+						HistoryEntry::Message(LLMMessage::User(parts)) => {
+							vstack([
+								text("User"),
+								text(&parts_text(parts)),
+								vstack(parts.iter().filter_map(|part| match part {
+									ContentPart::Image(image) => Some(text(&format!("[{} image]", image.media_type))),
+									ContentPart::Text { .. } => None,
+								})),
+							])
+							.spacing(10)
 						}
-						LLMMessage::System(content) => {
+						HistoryEntry::Message(LLMMessage::System(content)) => {
 							vstack([text("System"), text(&content)])
 								.spacing(10)
 						},
-						LLMMessage::Assistant(msg) => {
+						HistoryEntry::Message(LLMMessage::Assistant(msg)) => {
 							vstack([
 								hstack([
 									text("Assistant").grow(1),
@@ -225,10 +560,11 @@ fn project_view(project: &Project, state: &State) -> Item {
 							.spacing(10)
 							.grow(1)
 						},
-						LLMMessage::ToolResponse(res) => {
+						HistoryEntry::Message(LLMMessage::ToolResponse(res)) => {
 							vstack([
 								text("ToolResponse"),
 								text(&res.content),
+								vstack(res.images.iter().map(|image| text(&format!("[{} image]", image.media_type)))),
 							])
 							.spacing(10)
 						},
@@ -247,9 +583,21 @@ fn project_view(project: &Project, state: &State) -> Item {
 			if project.modified {
 				button("Save").id(SAVE_PRJECT_BUTTON)
 			} else {
-				text("Saved")
+				hstack([
+					text("Saved").grow(1),
+					button("Close").id(CLOSE_PROJECT_BUTTON),
+				])
+			},
+			tokens_view(state, project),
+			spending_view(state, project),
+			budget_view(project),
+			gen_params_view(project),
+			if project.model.provider == CUSTOM_PROVIDER {
+				custom_provider_view(project)
+			} else {
+				vstack([])
 			},
-			tokens_view(project),
+			fallback_models_view(project, state),
 			vstack([
 				text("Info"),
 				hstack([
@@ -266,11 +614,24 @@ fn project_view(project: &Project, state: &State) -> Item {
 				text("Max conversation turns"),
 				text_input().placeholder("max turns").id(MAX_CONVERSATION_TURNS).svalue(&state.max_conversation_turns.to_string()),
 				text("Current conversation turns"),
-				text(&state.conversation_turns.to_string()),
+				text(&project.conversation.turns.to_string()),
 				text("Max context size"),
 				text_input().svalue(&state.max_context_size.to_string()).id(MAX_CONTEXT_SIZE),
+				text("Max request attempts"),
+				text_input().svalue(&state.max_attempts.to_string()).id(MAX_ATTEMPTS),
+				text("Command timeout (seconds)"),
+				text_input()
+					.placeholder(&DEFAULT_TIMEOUT_SECS.to_string())
+					.svalue(&project.bash_timeout_secs.map(|secs| secs.to_string()).unwrap_or_default())
+					.id(BASH_TIMEOUT_INPUT),
+				button("Restart shell").id(RESTART_SHELL_BUTTON),
+				text("Display currency"),
+				text_input().placeholder("USD").svalue(&state.currency.code).id(CURRENCY_INPUT),
+				text("Exchange rate (per USD)"),
+				text_input().svalue(&state.currency.rate.to_string()).id(EXCHANGE_RATE_INPUT),
 			]).border("1px solid black").padding(5),
 			tools_list_view(project), 
+			processes_view(project),
 			forbidden_files(project),
 			todo_list_view(&project.todo_items)
 		]).spacing(10),
@@ -291,12 +652,13 @@ fn projects_tabs(state: &State) -> Item {
 		hstack(
 			state.projects.iter().enumerate().map(|(inx, project)| {
 				let modified = if project.modified { "*" } else { "" };
+				let running = if project.conversation.running { " (running)" } else { "" };
 				let name = if project.name.is_empty() {
 					format!("Project {}", inx + 1)
 				} else {
 					project.name.to_string()
 				};
-				nav_item(&format!("{} {}", name, modified))
+				nav_item(&format!("{} {}{}", name, modified, running))
 					.inx(inx as u32)
 			})
 		).spacing(10),
@@ -17,4 +17,20 @@ pub fn get_projects_dir() -> PathBuf {
 		std::fs::create_dir_all(&projects_dir).unwrap();
 	}
 	projects_dir
+}
+
+pub fn get_model_catalog_path() -> PathBuf {
+	get_app_dir().join("models.json")
+}
+
+pub fn get_cassettes_dir() -> PathBuf {
+	let cassettes_dir = get_app_dir().join("cassettes");
+	if !cassettes_dir.exists() {
+		std::fs::create_dir_all(&cassettes_dir).unwrap();
+	}
+	cassettes_dir
+}
+
+pub fn get_currency_path() -> PathBuf {
+	get_app_dir().join("currency.json")
 }
\ No newline at end of file
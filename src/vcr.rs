@@ -0,0 +1,221 @@
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use serde::Deserialize;
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::error::LLMError;
+use crate::llm::GenRequest;
+use crate::llm::SuccessfullGenResponse;
+use crate::utility::get_cassettes_dir;
+
+/// `record` or `replay`, anything else leaves the client talking to the providers.
+pub const VCR_ENV: &str = "PUPPYCODER_VCR";
+/// Name of the cassette in the cassettes dir, `session` if not set.
+pub const CASSETTE_ENV: &str = "PUPPYCODER_CASSETTE";
+
+/// Fields which only affect the UI and change after a response is recorded.
+const UI_FIELDS: [&str; 2] = ["expanded", "waiting_permission"];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum VcrMode {
+	Record,
+	Replay,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+	Response(SuccessfullGenResponse),
+	Error(LLMError),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Interaction {
+	/// Normalized request, see `normalize`.
+	pub request: Value,
+	pub outcome: Outcome,
+	#[serde(skip)]
+	pub replayed: bool,
+}
+
+/// Request fields which decide the response. The project id, streaming and
+/// the custom endpoint are left out so a session can be replayed on another
+/// machine, and strings are trimmed with unix line endings.
+pub fn normalize(req: &GenRequest) -> Value {
+	let mut value = serde_json::json!({
+		"model": req.model.key(),
+		"messages": req.messages,
+		"tools": req.tools.iter().map(|tool| tool.get_name()).collect::<Vec<_>>(),
+		"params": req.params,
+		"reasoning": req.reasoning,
+	});
+	normalize_value(&mut value);
+	value
+}
+
+fn normalize_value(value: &mut Value) {
+	match value {
+		Value::String(s) => *s = s.replace("\r\n", "\n").trim().to_string(),
+		Value::Array(items) => items.iter_mut().for_each(normalize_value),
+		Value::Object(map) => {
+			for field in UI_FIELDS {
+				map.remove(field);
+			}
+			map.values_mut().for_each(normalize_value);
+		}
+		_ => {}
+	}
+}
+
+/// Requests and responses of a session stored in a JSON file. Recording
+/// rewrites the file after every request so nothing is lost if the app
+/// crashes, which is usually when a recording is needed.
+pub struct Cassette {
+	mode: VcrMode,
+	path: PathBuf,
+	interactions: Mutex<Vec<Interaction>>,
+}
+
+impl Cassette {
+	/// Starts an empty cassette at `path`, replacing an earlier recording.
+	pub fn record(path: &Path) -> Cassette {
+		Cassette {
+			mode: VcrMode::Record,
+			path: path.to_path_buf(),
+			interactions: Mutex::new(Vec::new()),
+		}
+	}
+
+	pub fn replay(path: &Path) -> anyhow::Result<Cassette> {
+		let content = std::fs::read_to_string(path)?;
+		Ok(Cassette {
+			mode: VcrMode::Replay,
+			path: path.to_path_buf(),
+			interactions: Mutex::new(serde_json::from_str(&content)?),
+		})
+	}
+
+	/// Cassette selected with `PUPPYCODER_VCR` and `PUPPYCODER_CASSETTE`.
+	pub fn from_env() -> Option<Cassette> {
+		let name = std::env::var(CASSETTE_ENV).unwrap_or_else(|_| "session".to_string());
+		let path = get_cassettes_dir().join(format!("{}.json", name));
+		match std::env::var(VCR_ENV).ok()?.as_str() {
+			"record" => Some(Cassette::record(&path)),
+			"replay" => match Cassette::replay(&path) {
+				Ok(cassette) => Some(cassette),
+				Err(err) => {
+					log::error!("failed to load cassette {:?}: {:?}", path, err);
+					None
+				}
+			},
+			mode => {
+				log::warn!("unknown {} mode {}", VCR_ENV, mode);
+				None
+			}
+		}
+	}
+
+	pub fn mode(&self) -> VcrMode {
+		self.mode
+	}
+
+	pub fn add(&self, req: &GenRequest, res: &anyhow::Result<SuccessfullGenResponse>) {
+		let outcome = match res {
+			Ok(res) => Outcome::Response(res.clone()),
+			Err(err) => Outcome::Error(LLMError::classify(err)),
+		};
+		let mut interactions = self.interactions.lock().unwrap();
+		interactions.push(Interaction {
+			request: normalize(req),
+			outcome,
+			replayed: false,
+		});
+		let res = serde_json::to_string_pretty(&*interactions)
+			.map_err(anyhow::Error::from)
+			.and_then(|content| Ok(std::fs::write(&self.path, content)?));
+		if let Err(err) = res {
+			log::error!("failed to write cassette {:?}: {:?}", self.path, err);
+		}
+	}
+
+	/// Outcome of the first request not replayed yet which matches `req`.
+	/// Identical requests are answered in the order they were recorded.
+	pub fn find(&self, req: &GenRequest) -> anyhow::Result<SuccessfullGenResponse> {
+		let request = normalize(req);
+		let mut interactions = self.interactions.lock().unwrap();
+		let interaction = interactions.iter_mut()
+			.find(|i| !i.replayed && i.request == request)
+			.ok_or_else(|| LLMError::Other { message: "no recorded response matches the request".to_string() })?;
+		interaction.replayed = true;
+		match &interaction.outcome {
+			Outcome::Response(res) => Ok(res.clone()),
+			Outcome::Error(err) => Err(err.clone().into()),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::sync::Arc;
+	use crate::llm::GenResult;
+	use crate::llm::LLMClient;
+	use crate::llm::LLMMessage;
+	use crate::llm::LLMModel;
+	use crate::mock::MockProvider;
+	use crate::mock::MockScript;
+	use crate::mock::MockStep;
+	use crate::mock::MOCK_MODEL;
+	use crate::mock::MOCK_PROVIDER;
+
+	fn request(text: &str) -> GenRequest {
+		GenRequest {
+			model: LLMModel::new(MOCK_PROVIDER, MOCK_MODEL),
+			messages: vec![LLMMessage::user(text)],
+			..Default::default()
+		}
+	}
+
+	async fn response(client: &mut LLMClient, req: GenRequest) -> GenResult {
+		client.gen(req);
+		client.next().await.unwrap().1
+	}
+
+	#[tokio::test]
+	async fn test_record_and_replay() {
+		let path = std::env::temp_dir().join(format!("puppycoder-cassette-{}.json", std::process::id()));
+		let mock = MockProvider::new(MockScript {
+			steps: vec![MockStep {
+				content: "Woof".to_string(),
+				..Default::default()
+			}],
+		});
+		let mut client = LLMClient::new();
+		client.register(Arc::new(mock));
+		client.set_cassette(Cassette::record(&path));
+		assert!(matches!(response(&mut client, request("Hello")).await, GenResult::Response(_)));
+		// The script has one step so the second request fails, which is recorded too.
+		assert!(matches!(response(&mut client, request("Again")).await, GenResult::Error(_)));
+
+		// Replayed without the provider, and regardless of whitespace and streaming.
+		let mut client = LLMClient::new();
+		client.set_cassette(Cassette::replay(&path).unwrap());
+		std::fs::remove_file(&path).unwrap();
+		let mut req = request("Hello \r\n");
+		req.stream = true;
+		req.project_id = 3;
+		match response(&mut client, req).await {
+			GenResult::Response(res) => assert_eq!(res.msg.content, "Woof"),
+			res => panic!("unexpected result {:?}", res),
+		}
+		assert!(matches!(response(&mut client, request("Again")).await, GenResult::Error(LLMError::Other { .. })));
+		// Each recording is replayed once.
+		match response(&mut client, request("Hello")).await {
+			GenResult::Error(err) => assert_eq!(err.message(), "no recorded response matches the request"),
+			res => panic!("unexpected result {:?}", res),
+		}
+	}
+}